@@ -1,158 +1,190 @@
 use std::{
-    fs, io::{prelude::*, Read}, path::Path, process::Command
+    env, fs, process, time::Duration
 };
 
-use regex::Regex;
-use std::fs::File;
+use reqwest::blocking::Client;
+use tileset_conversion_server::{cache, config::Config, convert, freshness::{self, Revalidation}, migrate, retention::Retention, source::Source, store::{Entry, Key, Kind, Store}, tileset::{self, Tileset}, upstream::{self, Validators}};
 // use rust_fetcher::ThreadPool;
 // use num_cpus;
 
-const TILESERVER_URL: &str = "https://waapi.webatlas.no/3d-tiles/tileserver.fcgi/";
-const API_KEY: &str = "?api_key=DB124B20-9D21-4647-B65A-16C651553E48";
+fn main() {
+    let config = match Config::load(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => { println!("{}", e); process::exit(2); }
+    };
 
-const PATH_TILESET_DIR: &str = "tileset_cache/tilesets";
-const PATH_B3DM_DIR: &str = "tileset_cache/b3dms";
-const PATH_GLB_DIR: &str = "tileset_cache/glbs";
+    let removed = cache::remove_orphaned_temp_files(&config.cache_dir);
+    if removed > 0 {
+        println!("Removed {} temp files left behind in {}", removed, config.cache_dir);
+    }
 
-fn main() {
-    // Ensure the required directories exists
-    fs::create_dir_all(PATH_TILESET_DIR).expect(format!("Couldn't create required dir {}", PATH_TILESET_DIR).as_str());
-    fs::create_dir_all(PATH_B3DM_DIR).expect(format!("Couldn't create required dir {}", PATH_B3DM_DIR).as_str());
-    fs::create_dir_all(PATH_GLB_DIR).expect(format!("Couldn't create required dir {}", PATH_GLB_DIR).as_str());
-    
-    // let thread_count = num_cpus::get();
-    // let thread_pool = ThreadPool::new(thread_count);
-    let root_filename = "tileset.json";
-    let Ok(root_body) = handle_tileset(root_filename) else {
-        println!("Unable to fetch file {}", root_filename);
-        return;
+    let store = match migrate::open_store(&config) {
+        Ok(store) => store,
+        Err(e) => { println!("{}", e); process::exit(2); }
     };
+    config.cache_manager().enforce_all(&store);
+
+    // The cache misses the server recorded while offline, to be prefetched once online again
+    if config.offline {
+        let missing = store.missing();
+        println!("Offline, not fetching anything. {} tiles were requested but aren't cached:", missing.len());
+        for tile in &missing {
+            println!("  {} (requested {} times)", tile.url, tile.requests);
+        }
+        return;
+    }
 
-    // Fetch all referenced tilesets recursively 
-    fetch_tileset_and_models_recursively(&root_body); // &thread_pool,
+    let client = Client::new();
+    let sources = config.sources();
+    for source in &sources {
+        // let thread_count = num_cpus::get();
+        // let thread_pool = ThreadPool::new(thread_count);
+        let root_filename = "tileset.json";
+        let Ok(root_body) = handle_tileset(&client, &config, &store, source, root_filename) else {
+            println!("Unable to fetch file {}{}", source.route_prefix(), root_filename);
+            continue;
+        };
+
+        // Fetch all referenced tilesets recursively 
+        fetch_tileset_and_models_recursively(&client, &config, &store, source, root_filename, &root_body); // &thread_pool,
+    }
     println!("Fetched all tilesets and referenced models");
+
+    // Tiles cached above aren't missing anymore, what's left wasn't reached from the root tilesets
+    let missing = store.missing();
+    if !missing.is_empty() {
+        println!("Prefetching {} tiles requested while offline", missing.len());
+    }
+    for tile in missing {
+        let Some(source) = sources.iter().find(|source| source.namespace == tile.namespace) else {
+            println!("Skipping {} as its source isn't configured anymore", tile.path);
+            continue;
+        };
+        match tile.kind {
+            Kind::Tilesets => {
+                if let Ok(content) = handle_tileset(&client, &config, &store, source, &tile.path) {
+                    fetch_tileset_and_models_recursively(&client, &config, &store, source, &tile.path, &content);
+                }
+            }
+            _ => handle_model(&client, &config, &store, source, &tile.path),
+        }
+    }
 }
 
 /////// FETCH FUNCTIONS ////////
-fn fetch_tileset_and_models_recursively(body: &str) { //thread_pool: &ThreadPool, 
-    let re = Regex::new(r"(?<match>[0-9]*tileset.json|[0-9]+model.cmpt|[0-9]+model.b3dm|[0-9]+model)").unwrap();
-    let matches: Vec<_> = re.find_iter(&body).map(|m| m.as_str()).collect();
-    for m in matches.iter() {
-        if m.contains("tileset") {
-            if let Ok(content) = handle_tileset(m) {
-                fetch_tileset_and_models_recursively(&content);           
+fn fetch_tileset_and_models_recursively(client: &Client, config: &Config, store: &Store, source: &Source, path: &str, body: &str) { //thread_pool: &ThreadPool, 
+    let tileset = match Tileset::from_json(body) {
+        Ok(tileset) => tileset,
+        Err(e) => { println!("Unable to parse tileset {}: {}", path, e); return; }
+    };
+    for uri in tileset.content_uris() {
+        // Content URIs are relative to the tileset they are found in
+        let Some(child) = tileset::resolve_content_uri(&source.url, path, uri) else {
+            println!("Skipping {} as it isn't hosted by the tileserver", uri);
+            continue;
+        };
+        if tileset::is_tileset_path(&child) {
+            if let Ok(content) = handle_tileset(client, config, store, source, &child) {
+                fetch_tileset_and_models_recursively(client, config, store, source, &child, &content);
             }
         } else {
-            handle_model(m);
+            handle_model(client, config, store, source, &child);
         }
     }
 }
 
-fn handle_tileset(filename: &str) -> Result<String, String> {
-    let tileset_path = PATH_TILESET_DIR.to_string() + "/" + filename;
-    if !Path::new(&tileset_path).exists() {
-        println!("{} is not available locally. Fetching it.", filename);
-        let url = TILESERVER_URL.to_string() + filename + API_KEY; 
-        return request_and_cache_tileset(&url, filename);
-    } else {
-        let Ok(content) = fs::read_to_string(&tileset_path) else {
-            return Err(format!("Unable to read file {}", filename));
-        }; 
-        return Ok(content);
-    };
+fn handle_tileset(client: &Client, config: &Config, store: &Store, source: &Source, filename: &str) -> Result<String, String> {
+    let key = source.key(filename, "");
+    let cached = store.get(&key).and_then(|entry| Some((fs::read_to_string(&entry.path).ok()?, entry)));
+    match cached {
+        Some((content, entry)) => {
+            if !freshness::is_stale(&entry, Duration::from_secs(config.tileset_max_age_secs)) {
+                return Ok(content);
+            }
+            // Models of a changed tileset are removed, so they're fetched again below
+            println!("{} is stale. Revalidating it.", filename);
+            match freshness::revalidate_tileset(store, client, source, filename, &config.retry_policy()) {
+                Ok(Revalidation::Changed { contents, .. }) => {
+                    enforce_quota(config, store, Kind::Tilesets, &key);
+                    Ok(contents)
+                }
+                Ok(Revalidation::Unchanged) => Ok(content),
+                Err(e) => { println!("Unable to revalidate {}, using the cached copy: {}", filename, e); Ok(content) }
+            }
+        }
+        None => {
+            println!("{} is not available locally. Fetching it.", filename);
+            request_and_cache_tileset(client, config, store, source, filename)
+        }
+    }
 }
 
-fn request_and_cache_tileset(req_url: &str, file_name: &str) -> Result<String, String> {    
-    let Ok(mut response) = reqwest::blocking::get(req_url) else {
-        return Err(format!("Failed to fetch from: {}", req_url));
-    };
+fn request_and_cache_tileset(client: &Client, config: &Config, store: &Store, source: &Source, filename: &str) -> Result<String, String> {    
+    let (body, validators) = upstream::fetch_tileset(client, source, filename, &config.retry_policy()).map_err(|e| e.to_string())?;
 
-    let mut body = String::new();
-    if let Err(e) = response.read_to_string(&mut body) {
-        return Err(format!("Error when reading response to string: {}", e));
-    };
+    let key = source.key(filename, "");
+    store.put(&key, Kind::Tilesets, "application/json", body.as_bytes(), &validators, "")?;
+    enforce_quota(config, store, Kind::Tilesets, &key);
 
-    if let Err(e) = fs::write(format!("{}/{}", PATH_TILESET_DIR, file_name), &body) {
-        return Err(format!("Error when writing tileset to file: {}", e));
-    };
-
-    return Ok(body);
+    Ok(body)
 }
 
-fn handle_model(filename: &str) {
-    let filename_stemmed = Path::new(filename).file_stem().unwrap().to_str().unwrap();
-    let path_b3dm = PATH_B3DM_DIR.to_string() + "/" + filename_stemmed + ".b3dm";
-    let path_glb = PATH_GLB_DIR.to_string() + "/" + filename_stemmed + ".glb";
-    if !Path::new(&path_glb).exists() {
-        if !Path::new(&path_b3dm).exists() {
+fn handle_model(client: &Client, config: &Config, store: &Store, source: &Source, filename: &str) {
+    let model_key = source.key(filename, "");
+    let glb_key = model_key.variant(&convert::glb_variant(None, &convert::Options::default()));
+    // With source retention only the source model is kept, the server converts it on demand
+    let keep_glb = config.source_retention != Retention::Source;
+    if keep_glb && store.get(&glb_key).is_some() {
+        return;
+    }
+    let model = match store.get(&model_key) {
+        Some(model) => model,
+        None => {
             println!("{} is not available locally. Fetching it.", filename);
-            let url = TILESERVER_URL.to_string() + filename + API_KEY;
-            let was_success = request_and_cache_binary_model_file( &url, &path_b3dm);
-            if !was_success {
-                return; 
-            }
-        }   
-        // Convert the model file to a glb file and return it
-        if filename.contains("cmpt") { convert_cmpt_to_glb(filename_stemmed); } 
-        else { convert_b3dm_to_glb(filename, filename_stemmed); }   
+            let Some(model) = request_and_cache_binary_model_file(client, config, store, source, filename) else {
+                return;
+            };
+            model
+        }
+    };
+    if !keep_glb {
+        return;
+    }
+    // Convert the model file to a glb file
+    let temp = store.temp_path();
+    if let Err(e) = convert::convert_file(&model.path.to_string_lossy(), &temp.to_string_lossy(), None, &convert::Options::default(), config.converter) {
+        println!("Unable to convert {}: {}", filename, e);
+        return;
+    }
+    let params = convert::glb_params(config.converter, &model.blob);
+    if let Err(e) = store.put_file(&glb_key, Kind::Glbs, "model/gltf-binary", &temp, &Validators::default(), &params) {
+        println!("Unable to cache the conversion of {}: {}", filename, e);
+        return;
+    }
+    enforce_quota(config, store, Kind::Glbs, &glb_key);
+    if config.source_retention == Retention::Glb {
+        store.remove(&model_key);
     }
 }
 
-fn request_and_cache_binary_model_file(req_url: &str, target_file_path: &str) -> bool {
-    let Ok(response) = reqwest::blocking::get(req_url) else {
-        println!("Failed to fetch from: {}", req_url);
-        return false;
-    };
-
-    let mut file = match File::create(Path::new(&target_file_path)) {
-        Ok(file) => file,
-        Err(_) => return false,
+fn request_and_cache_binary_model_file(client: &Client, config: &Config, store: &Store, source: &Source, filename: &str) -> Option<Entry> {
+    let content = match upstream::fetch_model(client, source, filename, &config.retry_policy()) {
+        Ok(content) => content,
+        Err(e) => { println!("{}", e); return None; }
     };
 
-    let Ok(content) = response.bytes() else {
-        println!("Failed to unwrap bytes from the response:");
-        return false;
-    };
-
-    if let Err(e) = file.write_all(&content) {
-        println!("Error when writing model to file: {}", e);
-        return false;
-    };
-
-    return true;
+    let key = source.key(filename, "");
+    match store.put(&key, Kind::Models, "application/octet-stream", &content, &Validators::default(), "") {
+        Ok(entry) => {
+            enforce_quota(config, store, Kind::Models, &key);
+            Some(entry)
+        }
+        Err(e) => { println!("Error when caching model: {}", e); None }
+    }
 }
 
-/////// CONVERTER FUNCTIONS ////////
-fn convert_cmpt_to_glb(filename_stemmed: &str) {
-    // npx 3d-tiles-tools cmptToGlb -i ./specs/data/composite.cmpt -o ./output/extracted.glb
-    let cmd = format!("npx 3d-tiles-tools cmptToGlb -i {}/{}.b3dm -o {}/{}.glb", PATH_B3DM_DIR, &filename_stemmed, PATH_GLB_DIR, &filename_stemmed);
-    let _ = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(["/C", &cmd])
-            .output()
-            .expect("Error when upgrading tileset")
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .output()
-            .expect("Error when upgrading tileset")
-    };
+// The server and the fetcher share the cache, so both keep it within the quotas
+fn enforce_quota(config: &Config, store: &Store, kind: Kind, key: &Key) {
+    config.cache_manager().enforce(store, kind, Some(key));
 }
 
-fn convert_b3dm_to_glb(filename: &str, filename_stemmed: &str) {
-    // npx 3d-tiles-tools convertB3dmToGlb  -i ./specs/data/composite.cmpt -o ./output/extracted.glb
-    let cmd = format!("npx 3d-tiles-tools convertB3dmToGlb -i {}/{} -o {}/{}.glb", PATH_B3DM_DIR, &filename, PATH_GLB_DIR, &filename_stemmed);
-    let _ = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(["/C", &cmd])
-            .output()
-            .expect("Error when upgrading tileset")
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .output()
-            .expect("Error when upgrading tileset")
-    };
-}
\ No newline at end of file
@@ -0,0 +1,100 @@
+use serde_json::json;
+
+use crate::{
+    glb::{read_u32, Glb},
+    table::Table,
+};
+
+pub const MAGIC: &[u8; 4] = b"b3dm";
+const HEADER_LENGTH: usize = 28;
+
+// Tiles written before the feature table was introduced have a 20 or 24 byte
+// header. In those the fields we read as table lengths hit the start of the
+// batch table JSON or the glTF magic, both of which are at least this large.
+const LEGACY_HEADER_THRESHOLD: u32 = 570425344;
+
+/// A Batched 3D Model tile.
+pub struct B3dm {
+    pub version: u32,
+    pub feature_table: Table,
+    pub batch_table: Table,
+    pub glb: Vec<u8>,
+}
+
+impl B3dm {
+    /// Parse a b3dm tile, including tiles with one of the legacy headers.
+    pub fn from_bytes(bytes: &[u8]) -> Result<B3dm, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..4] != MAGIC {
+            return Err("Missing b3dm magic in tile header".to_string());
+        }
+        let version = read_u32(bytes, 4)?;
+        let byte_length = (read_u32(bytes, 8)? as usize).min(bytes.len());
+        let mut feature_table_json_length = read_u32(bytes, 12)?;
+        let mut feature_table_binary_length = read_u32(bytes, 16)?;
+        let mut batch_table_json_length = read_u32(bytes, 20)?;
+        let mut batch_table_binary_length = read_u32(bytes, 24)?;
+
+        let mut offset = HEADER_LENGTH;
+        let mut legacy_batch_length = None;
+        if batch_table_json_length >= LEGACY_HEADER_THRESHOLD {
+            // [magic, version, byteLength, batchLength, batchTableByteLength]
+            offset -= 8;
+            legacy_batch_length = Some(feature_table_json_length);
+            batch_table_json_length = feature_table_binary_length;
+            batch_table_binary_length = 0;
+            feature_table_json_length = 0;
+            feature_table_binary_length = 0;
+        } else if batch_table_binary_length >= LEGACY_HEADER_THRESHOLD {
+            // [magic, version, byteLength, batchTableJsonByteLength, batchTableBinaryByteLength, batchLength]
+            offset -= 4;
+            legacy_batch_length = Some(batch_table_json_length);
+            batch_table_json_length = feature_table_json_length;
+            batch_table_binary_length = feature_table_binary_length;
+            feature_table_json_length = 0;
+            feature_table_binary_length = 0;
+        }
+
+        let mut sections = Vec::with_capacity(4);
+        for length in [feature_table_json_length, feature_table_binary_length, batch_table_json_length, batch_table_binary_length] {
+            let end = offset + length as usize;
+            if end > byte_length {
+                return Err("b3dm tables exceed the tile length".to_string());
+            }
+            sections.push(&bytes[offset..end]);
+            offset = end;
+        }
+
+        let mut feature_table = Table::from_slices(sections[0], sections[1])?;
+        if let Some(batch_length) = legacy_batch_length {
+            feature_table.json = json!({ "BATCH_LENGTH": batch_length });
+        }
+        let batch_table = Table::from_slices(sections[2], sections[3])?;
+
+        Ok(B3dm { version, feature_table, batch_table, glb: bytes[offset..byte_length].to_vec() })
+    }
+
+    pub fn batch_length(&self) -> u32 {
+        self.feature_table.global_u32("BATCH_LENGTH").unwrap_or(0)
+    }
+
+    /// Extract the embedded glTF as a standalone GLB.
+    ///
+    /// An `RTC_CENTER` in the feature table is carried over as a `CESIUM_RTC`
+    /// extension, otherwise the embedded GLB is returned untouched.
+    pub fn to_glb(&self) -> Result<Vec<u8>, String> {
+        let Some(center) = self.feature_table.global_vec3("RTC_CENTER") else {
+            if self.glb.get(0..4) != Some(&crate::glb::MAGIC[..]) {
+                return Err("b3dm doesn't contain a binary glTF".to_string());
+            }
+            return Ok(self.glb.clone());
+        };
+        let mut glb = Glb::from_bytes(&self.glb)?;
+        glb.add_extension("CESIUM_RTC", json!({ "center": center }));
+        Ok(glb.to_bytes())
+    }
+}
+
+/// Convert the bytes of a b3dm tile to a GLB.
+pub fn b3dm_to_glb(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    B3dm::from_bytes(bytes)?.to_glb()
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+// Temp files are hidden and end in this, e.g. .123model.b3dm.4242-7.tmp
+const TEMP_SUFFIX: &str = ".tmp";
+
+// Temp files are only written for as long as one download or conversion takes.
+// Younger ones may belong to the server or fetcher running next to us.
+pub(crate) const ORPHAN_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Write a file so that it's either complete or not there at all.
+///
+/// The contents go to a temp file in the same directory, which is synced to
+/// disk and then renamed over `path`. A crash in between leaves only the temp
+/// file behind, which [`remove_orphaned_temp_files`] cleans up.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = temp_path(path);
+    let result = File::create(&temp).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    persist(&temp, path)
+}
+
+/// A unique temp file next to `path`, for writers that need a path rather than bytes.
+///
+/// Move it into place with [`persist`] once it's complete.
+pub fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().map_or_else(Default::default, |n| n.to_string_lossy());
+    let unique = format!("{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    path.with_file_name(format!(".{}.{}{}", name, unique, TEMP_SUFFIX))
+}
+
+/// Rename a complete temp file over `path`, and sync the directory so the rename survives a crash.
+pub fn persist(temp: &Path, path: &Path) -> io::Result<()> {
+    if let Err(e) = fs::rename(temp, path) {
+        let _ = fs::remove_file(temp);
+        return Err(e);
+    }
+    // Directories can't be opened as files on Windows, where renames are durable anyway
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Whether a file name is one of a temp file from [`temp_path`].
+pub fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(TEMP_SUFFIX)
+}
+
+/// Remove temp files left behind by crashed writers anywhere below `dir`.
+///
+/// Returns how many were removed.
+pub fn remove_orphaned_temp_files<P: AsRef<Path>>(dir: P) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            removed += remove_orphaned_temp_files(entry.path());
+            continue;
+        }
+        if !is_temp_file(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let age = entry.metadata().and_then(|m| m.modified()).ok().and_then(|m| SystemTime::now().duration_since(m).ok());
+        if age.is_some_and(|age| age >= ORPHAN_AGE) && fs::remove_file(entry.path()).is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
@@ -0,0 +1,63 @@
+use crate::glb::read_u32;
+
+pub const MAGIC: &[u8; 4] = b"cmpt";
+const HEADER_LENGTH: usize = 16;
+// Every inner tile starts with magic, version and byteLength
+const INNER_HEADER_LENGTH: usize = 12;
+// Composites nested deeper than this are rejected, as no tileserver writes them
+pub const MAX_NESTING: usize = 8;
+
+/// A Composite tile holding the raw bytes of each of its inner tiles.
+pub struct Cmpt<'a> {
+    pub version: u32,
+    pub tiles: Vec<&'a [u8]>,
+}
+
+impl<'a> Cmpt<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Cmpt<'a>, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..4] != MAGIC {
+            return Err("Missing cmpt magic in tile header".to_string());
+        }
+        let version = read_u32(bytes, 4)?;
+        let byte_length = (read_u32(bytes, 8)? as usize).min(bytes.len());
+        let tiles_length = read_u32(bytes, 12)?;
+
+        let mut tiles = Vec::new();
+        let mut offset = HEADER_LENGTH;
+        for i in 0..tiles_length {
+            if offset + INNER_HEADER_LENGTH > byte_length {
+                return Err(format!("cmpt ends before inner tile {} of {}", i + 1, tiles_length));
+            }
+            let tile_length = read_u32(bytes, offset + 8)? as usize;
+            if tile_length < INNER_HEADER_LENGTH || offset + tile_length > byte_length {
+                return Err(format!("Inner tile {} exceeds the cmpt length", i + 1));
+            }
+            tiles.push(&bytes[offset..offset + tile_length]);
+            offset += tile_length;
+        }
+
+        Ok(Cmpt { version, tiles })
+    }
+
+    /// All inner tiles in order, with the tiles of nested composites inlined.
+    ///
+    /// Fails when composites are nested more than [`MAX_NESTING`] levels deep.
+    pub fn flatten(&self) -> Result<Vec<&'a [u8]>, String> {
+        let mut tiles = Vec::with_capacity(self.tiles.len());
+        self.flatten_into(1, &mut tiles)?;
+        Ok(tiles)
+    }
+
+    fn flatten_into(&self, depth: usize, tiles: &mut Vec<&'a [u8]>) -> Result<(), String> {
+        for &tile in &self.tiles {
+            if !tile.starts_with(MAGIC) {
+                tiles.push(tile);
+            } else if depth == MAX_NESTING {
+                return Err(format!("cmpt is nested more than {} levels deep", MAX_NESTING));
+            } else {
+                Cmpt::from_bytes(tile)?.flatten_into(depth + 1, tiles)?;
+            }
+        }
+        Ok(())
+    }
+}
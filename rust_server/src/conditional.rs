@@ -0,0 +1,60 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::http::Request;
+
+/// A strong ETag for a body, derived from a hash of its contents.
+pub fn etag_for_bytes(bytes: &[u8]) -> String {
+    format_etag(&Sha256::digest(bytes))
+}
+
+/// Format a time as an HTTP-date, e.g. for `Last-Modified`.
+pub fn http_date(time: SystemTime) -> String {
+    httpdate::fmt_http_date(time)
+}
+
+/// Whether the client's cached copy is still current, so a 304 can be sent.
+///
+/// `If-None-Match` takes precedence over `If-Modified-Since` as in RFC 9110.
+pub fn is_not_modified(request: &Request, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        return if_none_match.split(',').map(str::trim).any(|tag| tag == "*" || weak_match(tag, etag));
+    }
+    match (request.header("If-Modified-Since").and_then(|d| httpdate::parse_http_date(d).ok()), last_modified) {
+        (Some(since), Some(modified)) => truncate_to_seconds(modified) <= since,
+        _ => false,
+    }
+}
+
+/// Whether a `Range` request may be served given its `If-Range` precondition.
+///
+/// An entity tag must match strongly, and a date must equal the last modification exactly.
+pub fn if_range_matches(request: &Request, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    let Some(if_range) = request.header("If-Range") else {
+        return true;
+    };
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        // Weak tags never match strongly
+        return !if_range.starts_with("W/") && !etag.starts_with("W/") && if_range == etag;
+    }
+    match (httpdate::parse_http_date(if_range), last_modified) {
+        (Ok(date), Some(modified)) => truncate_to_seconds(modified) == date,
+        _ => false,
+    }
+}
+
+fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// HTTP-dates only have a resolution of seconds
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+fn format_etag(hash: &[u8]) -> String {
+    let hex: String = hash[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
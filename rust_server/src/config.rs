@@ -0,0 +1,392 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    convert::Converter,
+    eviction::CacheManager,
+    retention::Retention,
+    source::{Auth, Source, SourceConfig},
+    store::Kind,
+    upstream::RetryPolicy,
+};
+
+/// Read when no `--config` is given and it exists in the working directory.
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+/// Environment variables are named after the option in upper case behind this, e.g. `TILESET_SERVER_PORT`.
+pub const ENV_PREFIX: &str = "TILESET_SERVER_";
+
+pub const USAGE: &str = "\
+Options, which may also be set in a TOML file or as environment variables
+named after the option in upper case with a TILESET_SERVER_ prefix, e.g.
+TILESET_SERVER_TILESERVER_URL. Named upstream sources can only be set in the
+TOML file:
+  --config <path>                 TOML file to read, defaults to config.toml
+  --tileserver-url <url>          Base URL of the upstream tileserver
+  --api-key <key>                 API key sent to the tileserver
+  --bind <addresses>              Comma separated addresses to listen on, e.g. 0.0.0.0,[::1]:8080
+  --port <port>                   Port of bind addresses without one, defaults to 7878
+  --cache-dir <path>              Holds the cached blobs and their index, shared with the fetcher
+  --threads <count>               Number of worker threads
+  --keep-alive-timeout-secs <s>   How long an idle persistent connection is kept open, defaults to 5
+  --max-requests-per-connection <n>  Requests served before a persistent connection is closed
+  --converter <name>              native or 3d-tiles-tools
+  --source-retention <policy>     Models kept on disk: both, glb or source (GLBs kept in memory)
+  --glb-memory-cache-mb <mb>      Size of the in-memory GLB cache of source retention, defaults to 256
+  --public-base-url <url>         Absolute base URL for content in served tilesets
+  --cache-control-tilesets <v>    Cache-Control header of tilesets
+  --cache-control-models <v>      Cache-Control header of models
+  --offline <bool>                Only serve from the cache and never contact upstream
+  --offline-placeholder-glb <b>   Serve an empty GLB instead of a 404 for models missing while offline
+  --tileset-max-age-secs <secs>   Age after which cached tilesets are revalidated upstream, 0 is never
+  --revalidate-in-background <b>  Serve stale tilesets while revalidating them instead of waiting
+  --precompress-tilesets <bool>   Store compressed tilesets next to the originals
+  --cors-allowed-origins <list>   Comma separated origins allowed to use CORS, or *
+  --retries <count>               Retries of failed upstream requests, defaults to 3
+  --retry-base-delay-ms <ms>      Backoff before the first retry, doubled for every further one
+  --retry-max-delay-ms <ms>       Upper bound of the backoff and of Retry-After
+  --breaker-threshold <count>     Consecutive upstream failures that make it fail fast
+  --breaker-cooldown-secs <secs>  How long to fail fast before probing the upstream again
+  --max-tileset-cache-mb <mb>     Size of the tileset cache, least recently used ones are evicted
+  --max-model-cache-mb <mb>       Size of the cache of upstream models, e.g. b3dm
+  --max-glb-cache-mb <mb>         Size of the cache of converted glbs, 0 is unlimited for all three";
+
+/// Settings shared by the server and the fetcher.
+///
+/// Each setting is taken from the first of the command line, the environment
+/// and the config file that has it, and otherwise has a default.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Base URL of the upstream tileserver served at the root, unless there are named `sources`.
+    pub tileserver_url: String,
+    /// Sent to the tileserver as the `api_key` query parameter.
+    pub api_key: Option<String>,
+    /// Named upstreams, each served below `/<name>/`.
+    pub sources: BTreeMap<String, SourceConfig>,
+    /// The addresses to listen on, e.g. `0.0.0.0`, `[::]`, `localhost` or `192.168.1.2:8080`.
+    pub bind: Vec<String>,
+    /// The port of bind addresses that don't have one.
+    pub port: u16,
+    pub cache_dir: String,
+    pub threads: usize,
+    /// Idle persistent connections are closed after this, or as soon as other connections wait for a worker.
+    pub keep_alive_timeout_secs: u64,
+    pub max_requests_per_connection: usize,
+    pub converter: Converter,
+    pub source_retention: Retention,
+    /// Converted GLBs kept in memory when only the source models are kept on disk.
+    pub glb_memory_cache_mb: u64,
+    /// e.g. `https://tiles.example.com/` for absolute content URLs instead of relative ones.
+    pub public_base_url: Option<String>,
+    /// Tilesets change when upstream does, while a converted model never changes under its name.
+    pub cache_control_tilesets: String,
+    pub cache_control_models: String,
+    /// Answer cache misses right away, and record them for the fetcher to prefetch later.
+    pub offline: bool,
+    pub offline_placeholder_glb: bool,
+    /// Cached tilesets older than this are revalidated with upstream, and their models removed if they changed.
+    pub tileset_max_age_secs: u64,
+    pub revalidate_in_background: bool,
+    pub precompress_tilesets: bool,
+    pub cors_allowed_origins: Vec<String>,
+    pub retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+    /// Cache quotas of all sources together, 0 is unlimited.
+    pub max_tileset_cache_mb: u64,
+    pub max_model_cache_mb: u64,
+    pub max_glb_cache_mb: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            tileserver_url: "https://waapi.webatlas.no/3d-tiles/tileserver.fcgi/".to_string(),
+            api_key: None,
+            sources: BTreeMap::new(),
+            bind: vec!["0.0.0.0".to_string()],
+            port: 7878,
+            cache_dir: "tileset_cache".to_string(),
+            threads: num_cpus::get(),
+            keep_alive_timeout_secs: 5,
+            max_requests_per_connection: 1000,
+            converter: Converter::Native,
+            source_retention: Retention::Both,
+            glb_memory_cache_mb: 256,
+            public_base_url: None,
+            cache_control_tilesets: "public, max-age=3600".to_string(),
+            cache_control_models: "public, max-age=86400".to_string(),
+            offline: false,
+            offline_placeholder_glb: false,
+            tileset_max_age_secs: 86_400,
+            revalidate_in_background: true,
+            precompress_tilesets: false,
+            cors_allowed_origins: Vec::new(),
+            retries: 3,
+            retry_base_delay_ms: 200,
+            retry_max_delay_ms: 10_000,
+            breaker_threshold: 5,
+            breaker_cooldown_secs: 30,
+            max_tileset_cache_mb: 0,
+            max_model_cache_mb: 0,
+            max_glb_cache_mb: 0,
+        }
+    }
+}
+
+const KEYS: [&str; 28] = [
+    "tileserver_url",
+    "api_key",
+    "bind",
+    "port",
+    "cache_dir",
+    "threads",
+    "keep_alive_timeout_secs",
+    "max_requests_per_connection",
+    "converter",
+    "source_retention",
+    "glb_memory_cache_mb",
+    "public_base_url",
+    "cache_control_tilesets",
+    "cache_control_models",
+    "offline",
+    "offline_placeholder_glb",
+    "tileset_max_age_secs",
+    "revalidate_in_background",
+    "precompress_tilesets",
+    "cors_allowed_origins",
+    "retries",
+    "retry_base_delay_ms",
+    "retry_max_delay_ms",
+    "breaker_threshold",
+    "breaker_cooldown_secs",
+    "max_tileset_cache_mb",
+    "max_model_cache_mb",
+    "max_glb_cache_mb",
+];
+
+impl Config {
+    /// Load the config from the command line arguments (without the program
+    /// name), the environment and the config file.
+    pub fn load<I: IntoIterator<Item = String>>(args: I) -> Result<Config, String> {
+        let mut config_path = None;
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(format!("Unexpected argument {:?}\n{}", arg, USAGE));
+            };
+            if flag == "help" {
+                return Err(USAGE.to_string());
+            }
+            // Both --threads 4 and --threads=4
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => match args.next() {
+                    Some(value) => (flag.to_string(), value),
+                    None => return Err(format!("Missing value for --{}\n{}", flag, USAGE)),
+                },
+            };
+            if name == "config" {
+                config_path = Some(value);
+            } else {
+                flags.push((name.replace('-', "_"), value));
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::from_file(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+        for key in KEYS {
+            let name = env_name(key);
+            if let Ok(value) = env::var(&name) {
+                config.set(key, &value).map_err(|e| format!("{} in {}", e, name))?;
+            }
+        }
+        for (key, value) in flags {
+            config.set(&key, &value).map_err(|e| format!("{}\n{}", e, USAGE))?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Unable to read config {}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))
+    }
+
+    /// Set a setting from its textual form, as given on the command line or in the environment.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let optional = |value: &str| if value.is_empty() { None } else { Some(value.to_string()) };
+        match key {
+            "tileserver_url" => self.tileserver_url = value.to_string(),
+            "api_key" => self.api_key = optional(value),
+            "bind" => self.bind = split_list(value),
+            "port" => self.port = value.parse().map_err(|_| format!("Invalid port {:?}", value))?,
+            "cache_dir" => self.cache_dir = value.to_string(),
+            "threads" => self.threads = value.parse().map_err(|_| format!("Invalid thread count {:?}", value))?,
+            "keep_alive_timeout_secs" => self.keep_alive_timeout_secs = parse_number(value)?,
+            "max_requests_per_connection" => self.max_requests_per_connection = parse_number(value)?,
+            "converter" => self.converter = value.parse()?,
+            "source_retention" => self.source_retention = value.parse()?,
+            "glb_memory_cache_mb" => self.glb_memory_cache_mb = parse_number(value)?,
+            "public_base_url" => self.public_base_url = optional(value),
+            "cache_control_tilesets" => self.cache_control_tilesets = value.to_string(),
+            "cache_control_models" => self.cache_control_models = value.to_string(),
+            "offline" => self.offline = parse_bool(value)?,
+            "offline_placeholder_glb" => self.offline_placeholder_glb = parse_bool(value)?,
+            "tileset_max_age_secs" => self.tileset_max_age_secs = parse_number(value)?,
+            "revalidate_in_background" => self.revalidate_in_background = parse_bool(value)?,
+            "precompress_tilesets" => self.precompress_tilesets = parse_bool(value)?,
+            "cors_allowed_origins" => self.cors_allowed_origins = split_list(value),
+            "retries" => self.retries = parse_number(value)?,
+            "retry_base_delay_ms" => self.retry_base_delay_ms = parse_number(value)?,
+            "retry_max_delay_ms" => self.retry_max_delay_ms = parse_number(value)?,
+            "breaker_threshold" => self.breaker_threshold = parse_number(value)?,
+            "breaker_cooldown_secs" => self.breaker_cooldown_secs = parse_number(value)?,
+            "max_tileset_cache_mb" => self.max_tileset_cache_mb = parse_number(value)?,
+            "max_model_cache_mb" => self.max_model_cache_mb = parse_number(value)?,
+            "max_glb_cache_mb" => self.max_glb_cache_mb = parse_number(value)?,
+            _ => return Err(format!("Unknown option {}", key)),
+        }
+        Ok(())
+    }
+
+    fn validate(&mut self) -> Result<(), String> {
+        if self.threads == 0 {
+            return Err("The thread count must be at least 1".to_string());
+        }
+        if self.keep_alive_timeout_secs == 0 {
+            return Err("The keep-alive timeout must be at least 1 second".to_string());
+        }
+        if self.max_requests_per_connection == 0 {
+            return Err("The requests per connection must be at least 1".to_string());
+        }
+        if self.breaker_threshold == 0 {
+            return Err("The circuit breaker threshold must be at least 1".to_string());
+        }
+        if self.bind.is_empty() {
+            return Err("At least one bind address is needed".to_string());
+        }
+        for name in self.sources.keys() {
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '?', '#']) {
+                return Err(format!("Invalid source name {:?}", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve the bind addresses, using `port` for those without one.
+    ///
+    /// A host name may resolve to several addresses, which are all listened on.
+    pub fn listen_addresses(&self) -> Result<Vec<SocketAddr>, String> {
+        let mut addresses = Vec::new();
+        for bind in &self.bind {
+            if let Ok(address) = bind.parse::<SocketAddr>() {
+                addresses.push(address);
+                continue;
+            }
+            // IPv6 addresses may be given with or without brackets when they have no port
+            if let Ok(ip) = bind.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+                addresses.push(SocketAddr::new(ip, self.port));
+                continue;
+            }
+            let resolved = match bind.rsplit_once(':') {
+                Some((_, port)) if port.parse::<u16>().is_ok() => bind.to_socket_addrs(),
+                _ => (bind.as_str(), self.port).to_socket_addrs(),
+            };
+            match resolved {
+                Ok(resolved) => addresses.extend(resolved),
+                Err(e) => return Err(format!("Unable to resolve bind address {}: {}", bind, e)),
+            }
+        }
+        addresses.dedup();
+        Ok(addresses)
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            base_delay: Duration::from_millis(self.retry_base_delay_ms),
+            max_delay: Duration::from_millis(self.retry_max_delay_ms),
+            breaker_threshold: self.breaker_threshold,
+            breaker_cooldown: Duration::from_secs(self.breaker_cooldown_secs),
+        }
+    }
+
+    /// A cache manager enforcing the quotas.
+    pub fn cache_manager(&self) -> CacheManager {
+        let quota = |mb: u64| (mb > 0).then(|| mb.saturating_mul(1024 * 1024));
+        CacheManager::new(&[
+            (Kind::Tilesets, quota(self.max_tileset_cache_mb)),
+            (Kind::Models, quota(self.max_model_cache_mb)),
+            (Kind::Glbs, quota(self.max_glb_cache_mb)),
+        ])
+    }
+
+    /// The upstream sources, which are the named ones if there are any.
+    ///
+    /// Without named sources `tileserver_url` is served at the root and cached
+    /// directly below `cache_dir`, as it was before sources were introduced.
+    pub fn sources(&self) -> Vec<Source> {
+        if self.sources.is_empty() {
+            return vec![self.root_source()];
+        }
+        self.sources.iter().map(|(name, source)| self.named_source(name, source)).collect()
+    }
+
+    /// The source serving a request path without its leading slash, and the path relative to that source.
+    pub fn route<'a>(&self, path: &'a str) -> Option<(Source, &'a str)> {
+        if self.sources.is_empty() {
+            return Some((self.root_source(), path));
+        }
+        let (name, rest) = path.split_once('/')?;
+        let source = self.sources.get(name)?;
+        Some((self.named_source(name, source), rest))
+    }
+
+    fn root_source(&self) -> Source {
+        let auth = match &self.api_key {
+            Some(api_key) => Auth::Query { name: "api_key".to_string(), value: api_key.clone() },
+            None => Auth::None,
+        };
+        Source::new(None, &self.tileserver_url, auth, "", self.cache_dir.clone())
+    }
+
+    fn named_source(&self, name: &str, source: &SourceConfig) -> Source {
+        let namespace = source.cache_namespace.as_deref().unwrap_or(name);
+        Source::new(Some(name), &source.url, source.auth.clone(), namespace, format!("{}/{}", self.cache_dir, namespace))
+    }
+}
+
+/// The environment variable of an option, e.g. `TILESET_SERVER_API_KEY` for `api_key`.
+pub fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect()
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid number {:?}", value))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" | "" => Ok(false),
+        _ => Err(format!("Invalid boolean {:?}", value)),
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    fs::{self, File},
+    path::Path,
+    process::Command,
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    b3dm, cache,
+    cmpt::{self, Cmpt},
+    glb::{self, Glb},
+    i3dm::{self, Instancing},
+    pnts,
+};
+
+/// Choices made when converting tiles to GLB.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    pub instancing: Instancing,
+}
+
+/// The tool that converts tiles to GLB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum Converter {
+    /// The converters in this crate.
+    #[default]
+    #[serde(rename = "native")]
+    Native,
+    /// The `3d-tiles-tools` npm package, run with `npx`.
+    #[serde(rename = "3d-tiles-tools")]
+    TilesTools,
+}
+
+impl FromStr for Converter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Converter, String> {
+        match s {
+            "native" => Ok(Converter::Native),
+            "3d-tiles-tools" => Ok(Converter::TilesTools),
+            _ => Err(format!("Unknown converter {:?}, expected native or 3d-tiles-tools", s)),
+        }
+    }
+}
+
+impl Converter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Converter::Native => "native",
+            Converter::TilesTools => "3d-tiles-tools",
+        }
+    }
+}
+
+/// The variant a GLB converted from a model is cached under, e.g. `glb;tile=2;baked`.
+pub fn glb_variant(inner_tile: Option<usize>, options: &Options) -> String {
+    let mut variant = "glb".to_string();
+    if let Some(index) = inner_tile { variant += &format!(";tile={}", index); }
+    if options.instancing == Instancing::Baked { variant += ";baked"; }
+    variant
+}
+
+/// How a GLB was converted, recorded with it in the cache index.
+pub fn glb_params(converter: Converter, source_blob: &str) -> String {
+    format!("converter={};source={}", converter.name(), source_blob)
+}
+
+/// Convert the tile in `path_model` and write the GLB to `path_glb`.
+///
+/// With `inner_tile` only that inner tile of a composite is converted. The
+/// 3d-tiles-tools converter only handles whole b3dm, i3dm and pnts tiles with
+/// the default options, so other conversions always use the native one.
+pub fn convert_file(path_model: &str, path_glb: &str, inner_tile: Option<usize>, options: &Options, converter: Converter) -> Result<(), String> {
+    let Ok(bytes) = fs::read(path_model) else {
+        return Err(format!("Unable to read file {}", path_model));
+    };
+
+    let tools_command = match bytes.get(0..4) {
+        Some(magic) if magic == b3dm::MAGIC => Some("convertB3dmToGlb"),
+        Some(magic) if magic == i3dm::MAGIC => Some("convertI3dmToGlb"),
+        Some(magic) if magic == pnts::MAGIC => Some("convertPntsToGlb"),
+        _ => None,
+    };
+    if let (Converter::TilesTools, None, Instancing::Extension, Some(command)) = (converter, inner_tile, options.instancing, tools_command) {
+        return run_3d_tiles_tools(command, path_model, path_glb);
+    }
+
+    let glb = match inner_tile {
+        Some(index) => inner_tile_to_glb(&bytes, index, options)?,
+        None => tile_to_glb(&bytes, options)?,
+    };
+    cache::write_atomic(path_glb, &glb).map_err(|e| format!("Error when writing glb to file: {}", e))
+}
+
+// e.g. npx 3d-tiles-tools convertB3dmToGlb -i tileset_cache/b3dms/123model.b3dm -o tileset_cache/glbs/.123model.glb.42-0.tmp -f
+fn run_3d_tiles_tools(command: &str, path_model: &str, path_glb: &str) -> Result<(), String> {
+    let path_glb = Path::new(path_glb);
+    if let Some(parent) = path_glb.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Unable to create dir {}: {}", parent.display(), e))?;
+    }
+    // The tool writes the GLB as it goes, so it only gets a temp file
+    let temp = cache::temp_path(path_glb);
+    let npx = if cfg!(target_os = "windows") { "npx.cmd" } else { "npx" };
+    let output = Command::new(npx)
+        .arg("3d-tiles-tools")
+        .arg(command)
+        .arg("-i")
+        .arg(path_model)
+        .arg("-o")
+        .arg(&temp)
+        .arg("-f")
+        .output()
+        .map_err(|e| format!("Unable to run 3d-tiles-tools: {}", e))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&temp);
+        return Err(format!("3d-tiles-tools {} failed: {}", command, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    File::open(&temp)
+        .and_then(|file| file.sync_all())
+        .and_then(|_| cache::persist(&temp, path_glb))
+        .map_err(|e| format!("Error when writing glb to file: {}", e))
+}
+
+/// Convert a tile of any supported format to a single GLB.
+///
+/// The inner tiles of a composite are merged into one asset. It fails if any
+/// of them can't be converted, rather than serving the tile with parts missing.
+pub fn tile_to_glb(bytes: &[u8], options: &Options) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(cmpt::MAGIC) {
+        return single_tile_to_glb(bytes, options);
+    }
+
+    let mut glbs = Vec::new();
+    for (i, tile) in Cmpt::from_bytes(bytes)?.flatten()?.into_iter().enumerate() {
+        let glb = single_tile_to_glb(tile, options).and_then(|glb| Glb::from_bytes(&glb));
+        glbs.push(glb.map_err(|e| format!("Unable to convert inner tile {} of composite: {}", i, e))?);
+    }
+    if glbs.is_empty() {
+        return Err("Composite doesn't contain any tiles".to_string());
+    }
+    Ok(glb::merge(glbs)?.to_bytes())
+}
+
+/// Convert a single inner tile of a composite to a GLB.
+///
+/// Tiles are counted in the order given by [`Cmpt::flatten`]. A tile that
+/// isn't a composite only has the inner tile 0, which is the tile itself.
+pub fn inner_tile_to_glb(bytes: &[u8], index: usize, options: &Options) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(cmpt::MAGIC) {
+        if index != 0 {
+            return Err(format!("Inner tile {} requested from a tile that isn't a composite", index));
+        }
+        return single_tile_to_glb(bytes, options);
+    }
+
+    let tiles = Cmpt::from_bytes(bytes)?.flatten()?;
+    let Some(tile) = tiles.get(index) else {
+        return Err(format!("Composite only holds {} inner tiles", tiles.len()));
+    };
+    single_tile_to_glb(tile, options)
+}
+
+fn single_tile_to_glb(bytes: &[u8], options: &Options) -> Result<Vec<u8>, String> {
+    match bytes.get(0..4) {
+        Some(magic) if magic == b3dm::MAGIC => b3dm::b3dm_to_glb(bytes),
+        Some(magic) if magic == i3dm::MAGIC => i3dm::i3dm_to_glb(bytes, options.instancing),
+        Some(magic) if magic == pnts::MAGIC => pnts::pnts_to_glb(bytes),
+        Some(magic) if magic == glb::MAGIC => Ok(bytes.to_vec()),
+        Some(magic) => Err(format!("Unsupported tile format {:?}", String::from_utf8_lossy(magic))),
+        None => Err("Tile is too short to hold a header".to_string()),
+    }
+}
@@ -0,0 +1,84 @@
+use crate::http::{Request, Response};
+
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+// Browsers only let scripts read the CORS-safelisted response headers unless told otherwise
+const EXPOSED_HEADERS: &str = "ETag, Last-Modified, Content-Length, Content-Range, Accept-Ranges, Content-Encoding, X-Cache-Miss-Reason";
+const PREFLIGHT_MAX_AGE: u32 = 86400;
+
+/// Cross-origin access for browser-based viewers.
+///
+/// Origins are matched exactly against an allowlist, e.g.
+/// `["https://qa.example.com", "http://localhost:8080"]`. A `*` entry allows any
+/// origin, and an empty allowlist disables CORS.
+#[derive(Clone, Debug, Default)]
+pub struct Cors {
+    allowed_origins: Vec<String>,
+}
+
+impl Cors {
+    pub fn new(allowed_origins: &[String]) -> Cors {
+        let allowed_origins = allowed_origins.iter().map(|o| o.trim().trim_end_matches('/').to_string()).filter(|o| !o.is_empty()).collect();
+        Cors { allowed_origins }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    /// The value of `Access-Control-Allow-Origin` for a request's `Origin`, if it's allowed.
+    pub fn allowed_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some("*");
+        }
+        self.allowed_origins.iter().any(|o| o == origin).then_some(origin)
+    }
+
+    /// Answer an `OPTIONS` request, which is a preflight if it has `Access-Control-Request-Method`.
+    ///
+    /// Preflights from origins that aren't allowed get no CORS headers, so the
+    /// browser refuses the actual request.
+    pub fn preflight(&self, request: &Request) -> Response {
+        let response = Response::new(204).with_header("Allow", ALLOWED_METHODS);
+        let (Some(origin), Some(method)) = (request.header("Origin"), request.header("Access-Control-Request-Method")) else {
+            return response;
+        };
+        let Some(allowed_origin) = self.allowed_origin(origin) else {
+            return response;
+        };
+        if method != "GET" && method != "HEAD" {
+            return response;
+        }
+
+        let mut response = response
+            .with_header("Access-Control-Allow-Origin", allowed_origin)
+            .with_header("Access-Control-Allow-Methods", ALLOWED_METHODS)
+            .with_header("Access-Control-Max-Age", &PREFLIGHT_MAX_AGE.to_string());
+        // Viewers send e.g. Range and If-None-Match, which aren't safelisted, so allow whatever was asked for
+        if let Some(headers) = request.header("Access-Control-Request-Headers") {
+            response = response.with_header("Access-Control-Allow-Headers", headers);
+        }
+        if allowed_origin != "*" {
+            response = response.with_vary("Origin");
+        }
+        response
+    }
+
+    /// Add the CORS headers for the request's origin to a response.
+    pub fn apply(&self, request: &Request, response: Response) -> Response {
+        if !self.is_enabled() {
+            return response;
+        }
+        let Some(allowed_origin) = request.header("Origin").and_then(|o| self.allowed_origin(o)) else {
+            // The response depends on the Origin even when it gets no CORS headers
+            return response.with_vary("Origin");
+        };
+        let response = response
+            .with_header("Access-Control-Allow-Origin", allowed_origin)
+            .with_header("Access-Control-Expose-Headers", EXPOSED_HEADERS);
+        if allowed_origin == "*" {
+            response
+        } else {
+            response.with_vary("Origin")
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+
+// Bodies smaller than this gain too little from compression to be worth it
+pub const MIN_COMPRESSED_LENGTH: usize = 1024;
+
+/// A content coding for response bodies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+/// How hard to compress, trading time for size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effort {
+    /// For bodies compressed while the client waits.
+    Fast,
+    /// For bodies compressed once and stored on disk.
+    Best,
+}
+
+impl Encoding {
+    /// The token used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// Pick the encoding for a response from the request's `Accept-Encoding` header.
+///
+/// The highest quality value wins, and brotli is preferred over gzip when
+/// the client accepts both equally as it compresses JSON noticeably better.
+pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+    let Some(accept_encoding) = accept_encoding else {
+        return Encoding::Identity;
+    };
+
+    let mut wildcard = None;
+    let mut qualities = [(Encoding::Brotli, None), (Encoding::Gzip, None)];
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if coding == "*" {
+            wildcard = Some(quality);
+        }
+        for (encoding, q) in qualities.iter_mut() {
+            if coding.eq_ignore_ascii_case(encoding.name()) || (*encoding == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip")) {
+                *q = Some(quality);
+            }
+        }
+    }
+
+    let mut best = (Encoding::Identity, 0.0);
+    for (encoding, q) in qualities {
+        let q = q.or(wildcard).unwrap_or(0.0);
+        if q > best.1 {
+            best = (encoding, q);
+        }
+    }
+    best.0
+}
+
+/// Compress a body with the given encoding.
+pub fn compress(bytes: &[u8], encoding: Encoding, effort: Effort) -> Result<Vec<u8>, String> {
+    match encoding {
+        Encoding::Identity => Ok(bytes.to_vec()),
+        Encoding::Gzip => {
+            let level = match effort {
+                Effort::Fast => Compression::default(),
+                Effort::Best => Compression::best(),
+            };
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(bytes).and_then(|_| encoder.finish()).map_err(|e| format!("Gzip compression failed: {}", e))
+        }
+        Encoding::Brotli => {
+            let quality = match effort {
+                Effort::Fast => 5,
+                Effort::Best => 11,
+            };
+            let mut compressed = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 64 * 1024, quality, 22);
+                encoder.write_all(bytes).map_err(|e| format!("Brotli compression failed: {}", e))?;
+            }
+            Ok(compressed)
+        }
+    }
+}
+
+/// The ETag of an encoded representation, derived from the ETag of the identity one.
+///
+/// Each encoding has different bytes, so a strong ETag has to differ as well.
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Identity => etag.to_string(),
+        _ => format!("{}-{}\"", etag.trim_end_matches('"'), encoding.name()),
+    }
+}
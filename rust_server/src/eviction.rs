@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use crate::store::{Key, Kind, Store};
+
+/// Keeps the cache below a byte quota per kind by evicting the least recently used entries.
+///
+/// Sizes and access times are kept in the index of the [`Store`], so they
+/// survive restarts and include what the fetcher cached.
+pub struct CacheManager {
+    quotas: HashMap<Kind, u64>,
+}
+
+impl CacheManager {
+    /// Create a manager with byte quotas per kind. Kinds without a quota are never evicted.
+    pub fn new(quotas: &[(Kind, Option<u64>)]) -> CacheManager {
+        let quotas = quotas.iter().filter_map(|&(kind, quota)| Some((kind, quota?))).collect();
+        CacheManager { quotas }
+    }
+
+    /// Evict entries of a kind that's over quota, other than `keep`, which was just added.
+    pub fn enforce(&self, store: &Store, kind: Kind, keep: Option<&Key>) {
+        let Some(&quota) = self.quotas.get(&kind) else {
+            return;
+        };
+        let evicted = store.evict(kind, quota, keep);
+        if evicted > 0 {
+            println!("Evicted {} cached entries to keep {:?} below {} bytes", evicted, kind, quota);
+        }
+    }
+
+    /// Evict entries of every kind that's over quota, e.g. after the quotas were lowered.
+    pub fn enforce_all(&self, store: &Store) {
+        for kind in [Kind::Tilesets, Kind::Models, Kind::Glbs] {
+            self.enforce(store, kind, None);
+        }
+    }
+}
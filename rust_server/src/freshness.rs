@@ -0,0 +1,74 @@
+use std::{fs, time::{Duration, SystemTime}};
+
+use reqwest::blocking::Client;
+
+use crate::{
+    source::Source,
+    store::{self, Entry, Kind, Store},
+    tileset::{self, Tileset},
+    upstream::{self, FetchError, RetryPolicy},
+};
+
+/// The outcome of revalidating a cached tileset.
+#[derive(Clone, Debug)]
+pub enum Revalidation {
+    Unchanged,
+    /// The tileset changed upstream and was cached again, and the models it referenced were removed.
+    Changed { contents: String, invalidated: usize },
+}
+
+/// Whether a cached tileset is older than `max_age` and has to be revalidated. A zero `max_age` never expires.
+pub fn is_stale(entry: &Entry, max_age: Duration) -> bool {
+    if max_age.is_zero() {
+        return false;
+    }
+    let age = store::unix_time(SystemTime::now()).saturating_sub(entry.fetched_at);
+    age >= max_age.as_secs()
+}
+
+/// Ask upstream whether a cached tileset changed, with a conditional request if its validators are known.
+///
+/// A changed tileset replaces the cached one, and the models cached for the
+/// content it referenced are removed, so they're fetched and converted again.
+pub fn revalidate_tileset(store: &Store, client: &Client, source: &Source, filename: &str, policy: &RetryPolicy) -> Result<Revalidation, FetchError> {
+    let key = source.key(filename, "");
+    let cached = store.get(&key);
+    let validators = cached.as_ref().map(|entry| entry.validators.clone()).unwrap_or_default();
+    let Some((contents, validators)) = upstream::fetch_tileset_if_modified(client, source, filename, &validators, policy)? else {
+        store.set_validated(&key, &validators);
+        return Ok(Revalidation::Unchanged);
+    };
+
+    // Upstreams without validators send the whole tileset every time
+    let previous = cached.and_then(|entry| fs::read_to_string(entry.path).ok());
+    if previous.as_deref() == Some(contents.as_str()) {
+        store.set_validated(&key, &validators);
+        return Ok(Revalidation::Unchanged);
+    }
+
+    store.put(&key, Kind::Tilesets, "application/json", contents.as_bytes(), &validators, "").map_err(FetchError::Cache)?;
+    let invalidated = previous.map_or(0, |previous| invalidate_models(store, source, filename, &previous));
+    println!("{} changed upstream, removed {} cached models it referenced", filename, invalidated);
+    Ok(Revalidation::Changed { contents, invalidated })
+}
+
+/// Remove the cached source models and GLBs of the models a tileset references.
+///
+/// Returns how many entries were removed. External tilesets have their own max-age and are left alone.
+pub fn invalidate_models(store: &Store, source: &Source, filename: &str, contents: &str) -> usize {
+    let Ok(tileset) = Tileset::from_json(contents) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for uri in tileset.content_uris() {
+        let Some(child) = tileset::resolve_content_uri(&source.url, filename, uri) else {
+            continue;
+        };
+        if tileset::is_tileset_path(&child) {
+            continue;
+        }
+        removed += store.remove_url(&source.namespace, &source.key(&child, "").url);
+    }
+    removed
+}
+
@@ -0,0 +1,350 @@
+use serde_json::Value;
+
+pub const MAGIC: &[u8; 4] = b"glTF";
+const HEADER_LENGTH: usize = 12;
+const CHUNK_HEADER_LENGTH: usize = 8;
+const CHUNK_TYPE_JSON: u32 = 0x4E4F534A;
+const CHUNK_TYPE_BIN: u32 = 0x004E4942;
+
+/// A binary glTF 2.0 asset split into its JSON and BIN chunks.
+pub struct Glb {
+    pub json: Value,
+    pub bin: Vec<u8>,
+}
+
+impl Glb {
+    /// Parse a GLB container.
+    ///
+    /// Only glTF 2.0 containers are supported. The BIN chunk is optional and
+    /// is left empty when the asset doesn't have one.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Glb, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..4] != MAGIC {
+            return Err("Missing glTF magic in binary glTF header".to_string());
+        }
+        let version = read_u32(bytes, 4)?;
+        if version != 2 {
+            return Err(format!("Unsupported binary glTF version {}", version));
+        }
+        let length = (read_u32(bytes, 8)? as usize).min(bytes.len());
+
+        let mut json = None;
+        let mut bin = Vec::new();
+        let mut offset = HEADER_LENGTH;
+        while offset + CHUNK_HEADER_LENGTH <= length {
+            let chunk_length = read_u32(bytes, offset)? as usize;
+            let chunk_type = read_u32(bytes, offset + 4)?;
+            let start = offset + CHUNK_HEADER_LENGTH;
+            let Some(chunk) = bytes.get(start..start + chunk_length) else {
+                return Err("Binary glTF chunk exceeds the container length".to_string());
+            };
+            match chunk_type {
+                CHUNK_TYPE_JSON => json = Some(parse_json(chunk)?),
+                CHUNK_TYPE_BIN if bin.is_empty() => bin = chunk.to_vec(),
+                _ => {} // Unknown chunks must be ignored according to the spec
+            }
+            offset = start + chunk_length;
+        }
+
+        let Some(json) = json else {
+            return Err("Binary glTF is missing its JSON chunk".to_string());
+        };
+        // Everything that edits the asset relies on this
+        if !json.is_object() {
+            return Err("Binary glTF JSON chunk isn't an object".to_string());
+        }
+        Ok(Glb { json, bin })
+    }
+
+    /// An asset with an empty scene, e.g. to stand in for a model that isn't available.
+    pub fn placeholder() -> Glb {
+        let json = serde_json::json!({ "asset": { "version": "2.0" }, "scene": 0, "scenes": [{ "nodes": [] }] });
+        Glb { json, bin: Vec::new() }
+    }
+
+    /// Serialize the asset as a GLB container, padding both chunks to 4 bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut json = serde_json::to_vec(&self.json).expect("glTF JSON is always serializable");
+        pad_to_four(&mut json, b' ');
+        let mut bin = self.bin.clone();
+        pad_to_four(&mut bin, 0);
+
+        let mut length = HEADER_LENGTH + CHUNK_HEADER_LENGTH + json.len();
+        if !bin.is_empty() {
+            length += CHUNK_HEADER_LENGTH + bin.len();
+        }
+
+        let mut bytes = Vec::with_capacity(length);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&(length as u32).to_le_bytes());
+        bytes.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&CHUNK_TYPE_JSON.to_le_bytes());
+        bytes.extend_from_slice(&json);
+        if !bin.is_empty() {
+            bytes.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&CHUNK_TYPE_BIN.to_le_bytes());
+            bytes.extend_from_slice(&bin);
+        }
+        bytes
+    }
+
+    /// Add a top-level glTF extension and list it in `extensionsUsed`.
+    pub fn add_extension(&mut self, name: &str, value: Value) {
+        self.add_extension_used(name);
+        let root = self.json.as_object_mut().expect("glTF JSON root is an object");
+        let extensions = root.entry("extensions").or_insert_with(|| Value::Object(Default::default()));
+        if let Some(extensions) = extensions.as_object_mut() {
+            extensions.insert(name.to_string(), value);
+        }
+    }
+
+    /// Append float data to the BIN chunk as a new buffer view and accessor.
+    ///
+    /// Returns the index of the accessor.
+    pub fn push_float_accessor(&mut self, values: &[f32], accessor_type: &str) -> Result<usize, String> {
+        pad_to_four(&mut self.bin, 0);
+        let byte_offset = self.bin.len();
+        for v in values {
+            self.bin.extend_from_slice(&v.to_le_bytes());
+        }
+        let components = match accessor_type {
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            _ => 1,
+        };
+
+        let root = self.json.as_object_mut().expect("glTF JSON root is an object");
+        let views = root.entry("bufferViews").or_insert_with(|| Value::Array(Vec::new())).as_array_mut().ok_or("glTF bufferViews isn't an array")?;
+        views.push(serde_json::json!({ "buffer": 0, "byteOffset": byte_offset, "byteLength": values.len() * 4 }));
+        let view = views.len() - 1;
+        let accessors = root.entry("accessors").or_insert_with(|| Value::Array(Vec::new())).as_array_mut().ok_or("glTF accessors isn't an array")?;
+        accessors.push(serde_json::json!({ "bufferView": view, "componentType": 5126, "count": values.len() / components, "type": accessor_type }));
+        let accessor = accessors.len() - 1;
+        root.insert("buffers".to_string(), serde_json::json!([{ "byteLength": self.bin.len() }]));
+        Ok(accessor)
+    }
+
+    /// List an extension in `extensionsUsed` unless it is already there.
+    pub fn add_extension_used(&mut self, name: &str) {
+        let root = self.json.as_object_mut().expect("glTF JSON root is an object");
+        let used = root.entry("extensionsUsed").or_insert_with(|| Value::Array(Vec::new()));
+        if let Some(used) = used.as_array_mut() {
+            if !used.iter().any(|e| e == name) {
+                used.push(Value::from(name));
+            }
+        }
+    }
+}
+
+/// Parse a JSON header from a tile or chunk, ignoring trailing padding.
+pub(crate) fn parse_json(bytes: &[u8]) -> Result<Value, String> {
+    let end = bytes.iter().rposition(|b| !matches!(b, b' ' | b'\0' | b'\n' | b'\r' | b'\t')).map_or(0, |i| i + 1);
+    if end == 0 {
+        return Ok(Value::Object(Default::default()));
+    }
+    serde_json::from_slice(&bytes[..end]).map_err(|e| format!("Invalid JSON header: {}", e))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    match bytes.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(format!("Unexpected end of data at byte {}", offset)),
+    }
+}
+
+pub(crate) fn pad_to_four(bytes: &mut Vec<u8>, padding: u8) {
+    while !bytes.len().is_multiple_of(4) {
+        bytes.push(padding);
+    }
+}
+
+// Top-level glTF arrays whose elements are referenced by index.
+const INDEXED_ARRAYS: [&str; 11] = ["accessors", "animations", "bufferViews", "cameras", "images", "materials", "meshes", "nodes", "samplers", "skins", "textures"];
+
+/// Merge several GLBs into one asset whose default scene holds the root nodes of all of them.
+///
+/// All binary chunks are concatenated into a single buffer. When the inputs
+/// disagree on their `CESIUM_RTC` center, each center is baked into a
+/// translation on a node wrapping that input's roots.
+pub fn merge(glbs: Vec<Glb>) -> Result<Glb, String> {
+    if glbs.len() == 1 {
+        return Ok(glbs.into_iter().next().unwrap());
+    }
+    let Some(first) = glbs.first() else {
+        return Err("No binary glTFs to merge".to_string());
+    };
+
+    let centers: Vec<Option<Value>> = glbs.iter().map(|g| g.json.pointer("/extensions/CESIUM_RTC/center").cloned()).collect();
+    let shared_center = if centers.windows(2).all(|w| w[0] == w[1]) { centers[0].clone() } else { None };
+
+    let mut merged = serde_json::json!({ "asset": first.json["asset"].clone(), "scene": 0 });
+    let mut roots = Vec::new();
+    let mut bin = Vec::new();
+    for (glb, center) in glbs.into_iter().zip(centers) {
+        let mut json = glb.json;
+        if json["buffers"].as_array().is_some_and(|buffers| buffers.iter().any(|b| b.get("uri").is_some())) {
+            return Err("Merging binary glTFs with external buffers isn't supported".to_string());
+        }
+
+        pad_to_four(&mut bin, 0);
+        let offsets: Vec<(&str, u64)> = INDEXED_ARRAYS.iter().map(|k| (*k, merged[*k].as_array().map_or(0, |a| a.len() as u64))).collect();
+        let offset = |key: &str| offsets.iter().find(|(k, _)| *k == key).map_or(0, |(_, o)| *o);
+        remap_indices(&mut json, &offset, bin.len() as u64);
+        bin.extend_from_slice(&glb.bin);
+
+        let scene = json["scene"].as_u64().unwrap_or(0) as usize;
+        let mut scene_roots = match json["scenes"].get(scene).and_then(|s| s["nodes"].as_array()) {
+            Some(nodes) => nodes.clone(),
+            None => Vec::new(),
+        };
+        for key in INDEXED_ARRAYS {
+            if let Some(Value::Array(values)) = json.get_mut(key).map(Value::take) {
+                let target = merged[key].as_array_mut().map(std::mem::take).unwrap_or_default();
+                merged[key] = Value::Array(target.into_iter().chain(values).collect());
+            }
+        }
+        if let (None, Some(c)) = (&shared_center, center) {
+            // CESIUM_RTC centers are in Z-up ECEF, while glTF nodes are Y-up
+            let translation = serde_json::json!([c[0], c[2], -c[1].as_f64().unwrap_or(0.0)]);
+            let wrapper = serde_json::json!({ "translation": translation, "children": scene_roots });
+            let mut nodes = merged["nodes"].as_array_mut().map(std::mem::take).unwrap_or_default();
+            nodes.push(wrapper);
+            scene_roots = vec![Value::from(nodes.len() - 1)];
+            merged["nodes"] = Value::Array(nodes);
+        }
+        roots.extend(scene_roots);
+
+        for key in ["extensionsUsed", "extensionsRequired"] {
+            for name in json[key].as_array().into_iter().flatten().filter(|name| *name != "CESIUM_RTC") {
+                let list = merged.as_object_mut().unwrap().entry(key).or_insert_with(|| Value::Array(Vec::new()));
+                if let Some(list) = list.as_array_mut().filter(|list| !list.contains(name)) {
+                    list.push(name.clone());
+                }
+            }
+        }
+        if let Some(extensions) = json["extensions"].as_object() {
+            for (name, value) in extensions.iter().filter(|(name, _)| *name != "CESIUM_RTC") {
+                let target = merged.as_object_mut().unwrap().entry("extensions").or_insert_with(|| Value::Object(Default::default()));
+                target.as_object_mut().unwrap().entry(name.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    merged["scenes"] = serde_json::json!([{ "nodes": roots }]);
+    if !bin.is_empty() {
+        merged["buffers"] = serde_json::json!([{ "byteLength": bin.len() }]);
+    }
+    let mut glb = Glb { json: merged, bin };
+    if let Some(center) = shared_center {
+        glb.add_extension("CESIUM_RTC", serde_json::json!({ "center": center }));
+    }
+    Ok(glb)
+}
+
+// Shift every index in a glTF document by the size of the arrays it will be appended to.
+fn remap_indices(json: &mut Value, offset: &dyn Fn(&str) -> u64, bin_offset: u64) {
+    for accessor in elements(json, "accessors") {
+        shift(accessor, "bufferView", offset("bufferViews"));
+        for part in ["indices", "values"] {
+            if let Some(part) = accessor.get_mut("sparse").and_then(|sparse| sparse.get_mut(part)) {
+                shift(part, "bufferView", offset("bufferViews"));
+            }
+        }
+    }
+    for view in elements(json, "bufferViews").filter_map(Value::as_object_mut) {
+        view.insert("buffer".to_string(), Value::from(0));
+        let byte_offset = view.get("byteOffset").and_then(Value::as_u64).unwrap_or(0);
+        view.insert("byteOffset".to_string(), Value::from(byte_offset + bin_offset));
+    }
+    for image in elements(json, "images") {
+        shift(image, "bufferView", offset("bufferViews"));
+    }
+    for texture in elements(json, "textures") {
+        shift(texture, "source", offset("images"));
+        shift(texture, "sampler", offset("samplers"));
+        if let Some(Value::Object(extensions)) = texture.get_mut("extensions") {
+            extensions.values_mut().for_each(|e| shift(e, "source", offset("images")));
+        }
+    }
+    for material in elements(json, "materials") {
+        shift_texture_infos(material, offset("textures"));
+    }
+    for mesh in elements(json, "meshes") {
+        for primitive in elements(mesh, "primitives") {
+            shift_all(primitive.get_mut("attributes"), offset("accessors"));
+            shift(primitive, "indices", offset("accessors"));
+            shift(primitive, "material", offset("materials"));
+            for target in elements(primitive, "targets") {
+                shift_all(Some(target), offset("accessors"));
+            }
+            if let Some(draco) = primitive.pointer_mut("/extensions/KHR_draco_mesh_compression") {
+                shift(draco, "bufferView", offset("bufferViews"));
+            }
+        }
+    }
+    for node in elements(json, "nodes") {
+        shift(node, "mesh", offset("meshes"));
+        shift(node, "camera", offset("cameras"));
+        shift(node, "skin", offset("skins"));
+        shift_all(node.get_mut("children"), offset("nodes"));
+        if let Some(attributes) = node.pointer_mut("/extensions/EXT_mesh_gpu_instancing/attributes") {
+            shift_all(Some(attributes), offset("accessors"));
+        }
+    }
+    for skin in elements(json, "skins") {
+        shift(skin, "inverseBindMatrices", offset("accessors"));
+        shift(skin, "skeleton", offset("nodes"));
+        shift_all(skin.get_mut("joints"), offset("nodes"));
+    }
+    for animation in elements(json, "animations") {
+        for sampler in elements(animation, "samplers") {
+            shift(sampler, "input", offset("accessors"));
+            shift(sampler, "output", offset("accessors"));
+        }
+        for channel in elements(animation, "channels") {
+            if let Some(target) = channel.get_mut("target") {
+                shift(target, "node", offset("nodes"));
+            }
+        }
+    }
+    for scene in elements(json, "scenes") {
+        shift_all(scene.get_mut("nodes"), offset("nodes"));
+    }
+}
+
+fn elements<'a>(json: &'a mut Value, key: &str) -> impl Iterator<Item = &'a mut Value> {
+    json.get_mut(key).and_then(Value::as_array_mut).into_iter().flatten()
+}
+
+fn shift(object: &mut Value, key: &str, offset: u64) {
+    if let Some(index) = object.get(key).and_then(Value::as_u64) {
+        object[key] = Value::from(index + offset);
+    }
+}
+
+// Shift every index in an array or in the values of an object, e.g. primitive attributes.
+fn shift_all(values: Option<&mut Value>, offset: u64) {
+    let indices: Vec<&mut Value> = match values {
+        Some(Value::Array(values)) => values.iter_mut().collect(),
+        Some(Value::Object(values)) => values.values_mut().collect(),
+        _ => return,
+    };
+    for index in indices {
+        if let Some(i) = index.as_u64() {
+            *index = Value::from(i + offset);
+        }
+    }
+}
+
+// Texture references in materials and their extensions are all named `*Texture`.
+fn shift_texture_infos(value: &mut Value, offset: u64) {
+    if let Value::Object(object) = value {
+        for (key, child) in object.iter_mut() {
+            if key.ends_with("Texture") {
+                shift(child, "index", offset);
+            }
+            shift_texture_infos(child, offset);
+        }
+    }
+}
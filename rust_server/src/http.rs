@@ -0,0 +1,352 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+};
+
+// Upper bound for the request line and headers together
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// A parsed HTTP/1.x request.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The percent-decoded path, always starting with a slash.
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    /// The query as it was sent, without the leading `?`.
+    pub raw_query: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The connection was closed before a request started.
+    Closed,
+    /// The request isn't valid HTTP/1.x and should be answered with a 400.
+    Malformed(String),
+    Io(io::Error),
+}
+
+impl Request {
+    /// The value of a header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// The value of the first query parameter with the given name.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// The query as it was sent without the parameters named in `except`, e.g.
+    /// `?v=3` to forward upstream, or an empty string if nothing is left.
+    pub fn query_string(&self, except: &[&str]) -> String {
+        let kept: Vec<&str> = self.raw_query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+                percent_decode(name, true).is_ok_and(|name| !except.contains(&name.as_str()))
+            })
+            .collect();
+        if kept.is_empty() { String::new() } else { format!("?{}", kept.join("&")) }
+    }
+}
+
+/// Read a single request, including its body, from a buffered stream.
+///
+/// Request bodies are read so the stream is left at the start of the next
+/// request, even though no endpoint uses them.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, RequestError> {
+    let mut head_length = 0;
+    let mut request_line = String::new();
+    // Robust servers ignore empty lines before the request line (RFC 9112 section 2.2)
+    while request_line.is_empty() {
+        let Some(line) = read_line(reader, &mut head_length)? else {
+            return Err(RequestError::Closed);
+        };
+        request_line = line;
+    }
+
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(RequestError::Malformed(format!("Invalid request line {:?}", request_line)));
+    };
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err(RequestError::Malformed(format!("Invalid method {:?}", method)));
+    }
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(RequestError::Malformed(format!("Unsupported HTTP version {:?}", version)));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let Some(line) = read_line(reader, &mut head_length)? else {
+            return Err(RequestError::Malformed("Connection closed in the middle of the headers".to_string()));
+        };
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(RequestError::Malformed(format!("Invalid header {:?}", line)));
+        };
+        if name.is_empty() || name.ends_with([' ', '\t']) {
+            return Err(RequestError::Malformed(format!("Invalid header name {:?}", name)));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    let (path, query, raw_query) = parse_target(target)?;
+    let mut request = Request { method: method.to_string(), path, query, raw_query, version: version.to_string(), headers, body: Vec::new() };
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(RequestError::Malformed("Chunked request bodies aren't supported".to_string()));
+    }
+    if let Some(length) = request.header("Content-Length") {
+        let Ok(length) = length.parse::<usize>() else {
+            return Err(RequestError::Malformed(format!("Invalid Content-Length {:?}", length)));
+        };
+        if length > MAX_BODY_LENGTH {
+            return Err(RequestError::Malformed(format!("Request body of {} bytes is too large", length)));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).map_err(RequestError::Io)?;
+    }
+
+    Ok(request)
+}
+
+// Read a CRLF (or bare LF) terminated line, or None at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R, head_length: &mut usize) -> Result<Option<String>, RequestError> {
+    let mut line = Vec::new();
+    let limit = (MAX_HEAD_LENGTH - *head_length) as u64 + 1;
+    let read = reader.by_ref().take(limit).read_until(b'\n', &mut line).map_err(RequestError::Io)?;
+    *head_length += read;
+    if read == 0 {
+        return Ok(None);
+    }
+    if *head_length > MAX_HEAD_LENGTH {
+        return Err(RequestError::Malformed("Request headers are too large".to_string()));
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(RequestError::Malformed("Connection closed in the middle of a line".to_string()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    // Header values may be Latin-1, which we never need to interpret
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+// Decoded name and value pairs
+type QueryParams = Vec<(String, String)>;
+
+// Split an origin-form request target into its decoded path and query parameters, and the raw query.
+fn parse_target(target: &str) -> Result<(String, QueryParams, String), RequestError> {
+    if !target.starts_with('/') {
+        return Err(RequestError::Malformed(format!("Unsupported request target {:?}", target)));
+    }
+    let target = target.split('#').next().unwrap_or(target);
+    let (path, raw) = target.split_once('?').unwrap_or((target, ""));
+
+    let path = percent_decode(path, false)?;
+    let query = raw
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name, true)?, percent_decode(value, true)?))
+        })
+        .collect::<Result<_, RequestError>>()?;
+    Ok((path, query, raw.to_string()))
+}
+
+fn percent_decode(s: &str, plus_as_space: bool) -> Result<String, RequestError> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).filter(|h| h.iter().all(u8::is_ascii_hexdigit)).and_then(|h| std::str::from_utf8(h).ok());
+                let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) else {
+                    return Err(RequestError::Malformed(format!("Invalid percent-encoding in {:?}", s)));
+                };
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(decoded).map_err(|_| RequestError::Malformed(format!("Request target {:?} isn't valid UTF-8", s)))
+}
+
+// Large bodies are copied from disk in chunks of this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// An HTTP/1.1 response.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// `length` bytes of a file starting at `offset`, streamed without reading it all into memory.
+    File { file: File, offset: u64, length: u64 },
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File { length, .. } => *length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The part of a body selected by a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No usable range was requested, so the whole body is sent with a 200.
+    Full,
+    /// The inclusive byte range to send with a 206.
+    Partial { start: u64, end: u64 },
+    /// The range lies outside the body and gets a 416.
+    Unsatisfiable,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response { status, headers: Vec::new(), body: Body::Bytes(Vec::new()) }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Add a request header the response varies by to its `Vary` header, which lists each of them once.
+    pub fn with_vary(mut self, name: &str) -> Response {
+        let vary = self.headers.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case("Vary"));
+        match vary {
+            Some((_, value)) if value.split(',').any(|v| v.trim().eq_ignore_ascii_case(name)) => {}
+            Some((_, value)) => *value = format!("{}, {}", value, name),
+            None => self.headers.push(("Vary".to_string(), name.to_string())),
+        }
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Response {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    pub fn with_file(mut self, file: File, offset: u64, length: u64) -> Response {
+        self.body = Body::File { file, offset, length };
+        self
+    }
+
+    /// Write the status line, the headers with a `Content-Length` and the body.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(self.head().as_bytes())?;
+
+        match self.body {
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::File { mut file, offset, length } => {
+                file.seek(SeekFrom::Start(offset))?;
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let mut remaining = length;
+                while remaining > 0 {
+                    let want = remaining.min(CHUNK_SIZE as u64) as usize;
+                    let read = file.read(&mut chunk[..want])?;
+                    if read == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than its response"));
+                    }
+                    writer.write_all(&chunk[..read])?;
+                    remaining -= read as u64;
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    /// Write the response to a `HEAD` request, which has the status and headers of a `GET`, including its `Content-Length`, but no body.
+    pub fn write_head_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(self.head().as_bytes())?;
+        writer.flush()
+    }
+
+    fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        // A 304 describes the representation the client already has, so it has no length of its own
+        if self.status != 304 && self.status != 204 {
+            head += &format!("Content-Length: {}\r\n", self.body.len());
+        }
+        head + "\r\n"
+    }
+}
+
+/// Select the byte range of a body of `length` bytes requested by a `Range` header.
+///
+/// Only single ranges are honoured. Requests for several ranges get the whole
+/// body, which RFC 9110 allows in place of a multipart response.
+pub fn byte_range(range: Option<&str>, length: u64) -> ByteRange {
+    let Some(spec) = range.and_then(|r| r.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-500 is the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || length == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (length.saturating_sub(suffix), length - 1)
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, length.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        _ => return ByteRange::Full,
+    };
+    if start >= length {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial { start, end }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
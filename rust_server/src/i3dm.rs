@@ -0,0 +1,349 @@
+use serde_json::{json, Value};
+
+use crate::{
+    glb::{read_u32, Glb},
+    table::Table,
+};
+
+pub const MAGIC: &[u8; 4] = b"i3dm";
+const HEADER_LENGTH: usize = 32;
+
+// WGS84 radii used to find the surface normal for EAST_NORTH_UP instances
+const WGS84_RADIUS_EQUATOR: f64 = 6378137.0;
+const WGS84_RADIUS_POLAR: f64 = 6356752.314245179;
+
+type Mat4 = [f64; 16]; // Column-major, like glTF
+
+/// How the instances of an i3dm tile are represented in the converted GLB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Instancing {
+    /// One node per mesh with an `EXT_mesh_gpu_instancing` extension.
+    #[default]
+    Extension,
+    /// One node per mesh and instance, for clients without the extension.
+    Baked,
+}
+
+/// The placement of a single instance in the tile's Z-up frame.
+pub struct Instance {
+    pub translation: [f64; 3],
+    /// Columns are the right, up and forward directions of the instance.
+    pub rotation: [[f64; 3]; 3],
+    pub scale: [f64; 3],
+}
+
+/// An Instanced 3D Model tile.
+pub struct I3dm {
+    pub version: u32,
+    pub feature_table: Table,
+    pub batch_table: Table,
+    /// 0 when `gltf` is a URI to an external glTF, 1 when it is an embedded GLB.
+    pub gltf_format: u32,
+    pub gltf: Vec<u8>,
+}
+
+impl I3dm {
+    pub fn from_bytes(bytes: &[u8]) -> Result<I3dm, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..4] != MAGIC {
+            return Err("Missing i3dm magic in tile header".to_string());
+        }
+        let version = read_u32(bytes, 4)?;
+        let byte_length = (read_u32(bytes, 8)? as usize).min(bytes.len());
+        let gltf_format = read_u32(bytes, 28)?;
+
+        let mut offset = HEADER_LENGTH;
+        let mut sections = Vec::with_capacity(4);
+        for field in [12, 16, 20, 24] {
+            let end = offset + read_u32(bytes, field)? as usize;
+            if end > byte_length {
+                return Err("i3dm tables exceed the tile length".to_string());
+            }
+            sections.push(&bytes[offset..end]);
+            offset = end;
+        }
+
+        Ok(I3dm {
+            version,
+            feature_table: Table::from_slices(sections[0], sections[1])?,
+            batch_table: Table::from_slices(sections[2], sections[3])?,
+            gltf_format,
+            gltf: bytes[offset..byte_length].to_vec(),
+        })
+    }
+
+    pub fn instances_length(&self) -> usize {
+        self.feature_table.global_u32("INSTANCES_LENGTH").unwrap_or(0) as usize
+    }
+
+    /// Decode the position, orientation and scale of every instance from the feature table.
+    pub fn instances(&self) -> Result<Vec<Instance>, String> {
+        let table = &self.feature_table;
+        let count = self.instances_length();
+        let rtc_center = table.global_vec3("RTC_CENTER").unwrap_or([0.0; 3]);
+
+        let positions: Vec<f64> = if let Some(positions) = table.property_f32("POSITION", count, 3)? {
+            positions.into_iter().map(f64::from).collect()
+        } else if let Some(quantized) = table.property_u16("POSITION_QUANTIZED", count, 3)? {
+            let (Some(offset), Some(scale)) = (table.global_vec3("QUANTIZED_VOLUME_OFFSET"), table.global_vec3("QUANTIZED_VOLUME_SCALE")) else {
+                return Err("i3dm has POSITION_QUANTIZED without a quantized volume".to_string());
+            };
+            quantized.iter().enumerate().map(|(i, q)| offset[i % 3] + *q as f64 / 65535.0 * scale[i % 3]).collect()
+        } else {
+            return Err("i3dm has neither POSITION nor POSITION_QUANTIZED".to_string());
+        };
+
+        let normals = match (table.property_f32("NORMAL_UP", count, 3)?, table.property_f32("NORMAL_RIGHT", count, 3)?) {
+            (Some(up), Some(right)) => Some((up.into_iter().map(f64::from).collect::<Vec<_>>(), right.into_iter().map(f64::from).collect::<Vec<_>>())),
+            _ => match (table.property_u16("NORMAL_UP_OCT32P", count, 2)?, table.property_u16("NORMAL_RIGHT_OCT32P", count, 2)?) {
+                (Some(up), Some(right)) => Some((oct_decode_all(&up), oct_decode_all(&right))),
+                _ => None,
+            },
+        };
+        let east_north_up = table.json.get("EAST_NORTH_UP").and_then(Value::as_bool).unwrap_or(false);
+        let scales = table.property_f32("SCALE", count, 1)?;
+        let scales_non_uniform = table.property_f32("SCALE_NON_UNIFORM", count, 3)?;
+
+        let mut instances = Vec::with_capacity(count);
+        for i in 0..count {
+            let translation = [positions[i * 3], positions[i * 3 + 1], positions[i * 3 + 2]];
+            let rotation = if let Some((up, right)) = &normals {
+                let up = [up[i * 3], up[i * 3 + 1], up[i * 3 + 2]];
+                let right = [right[i * 3], right[i * 3 + 1], right[i * 3 + 2]];
+                [right, up, cross(right, up)]
+            } else if east_north_up {
+                east_north_up_frame([translation[0] + rtc_center[0], translation[1] + rtc_center[1], translation[2] + rtc_center[2]])
+            } else {
+                [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+            };
+            let mut scale = [1.0; 3];
+            if let Some(scales) = &scales {
+                scale = [scales[i] as f64; 3];
+            }
+            if let Some(scales) = &scales_non_uniform {
+                for (axis, s) in scale.iter_mut().enumerate() {
+                    *s *= scales[i * 3 + axis] as f64;
+                }
+            }
+            instances.push(Instance { translation, rotation, scale });
+        }
+        Ok(instances)
+    }
+
+    /// Convert the tile to a GLB with every mesh of the embedded glTF instanced.
+    ///
+    /// Instances are placed relative to an `RTC_CENTER`, which is carried over
+    /// as a `CESIUM_RTC` extension like for b3dm tiles.
+    pub fn to_glb(&self, instancing: Instancing) -> Result<Vec<u8>, String> {
+        if self.gltf_format == 0 {
+            return Err("i3dm references an external glTF, which isn't supported".to_string());
+        }
+        let mut glb = Glb::from_bytes(&self.gltf)?;
+        let instances: Vec<Mat4> = self.instances()?.iter().map(gltf_instance_matrix).collect();
+
+        let scene = glb.json["scene"].as_u64().unwrap_or(0) as usize;
+        let roots: Vec<u64> = glb.json["scenes"].get(scene).and_then(|s| s["nodes"].as_array()).into_iter().flatten().filter_map(Value::as_u64).collect();
+        let mut mesh_nodes = Vec::new();
+        for root in roots {
+            collect_mesh_nodes(&glb.json, root as usize, IDENTITY, &mut mesh_nodes);
+        }
+
+        let mut new_nodes = Vec::new();
+        for (mesh, global) in mesh_nodes {
+            let transforms: Vec<Mat4> = instances.iter().map(|instance| multiply(instance, &global)).collect();
+            match instancing {
+                Instancing::Extension => {
+                    let (translations, rotations, scales) = decompose_all(&transforms);
+                    let attributes = json!({
+                        "TRANSLATION": glb.push_float_accessor(&to_f32(&translations), "VEC3")?,
+                        "ROTATION": glb.push_float_accessor(&to_f32(&rotations), "VEC4")?,
+                        "SCALE": glb.push_float_accessor(&to_f32(&scales), "VEC3")?,
+                    });
+                    new_nodes.push(json!({ "mesh": mesh, "extensions": { "EXT_mesh_gpu_instancing": { "attributes": attributes } } }));
+                }
+                Instancing::Baked => {
+                    new_nodes.extend(transforms.iter().map(|m| json!({ "mesh": mesh, "matrix": m })));
+                }
+            }
+        }
+        if instancing == Instancing::Extension {
+            glb.add_extension_used("EXT_mesh_gpu_instancing");
+        }
+
+        let nodes = glb.json.as_object_mut().unwrap().entry("nodes").or_insert_with(|| Value::Array(Vec::new()));
+        let nodes = nodes.as_array_mut().ok_or("glTF nodes isn't an array")?;
+        let first = nodes.len();
+        nodes.extend(new_nodes);
+        let scene_nodes: Vec<usize> = (first..nodes.len()).collect();
+        glb.json["scenes"] = json!([{ "nodes": scene_nodes }]);
+        glb.json["scene"] = json!(0);
+
+        if let Some(center) = self.feature_table.global_vec3("RTC_CENTER") {
+            glb.add_extension("CESIUM_RTC", json!({ "center": center }));
+        }
+        Ok(glb.to_bytes())
+    }
+}
+
+/// Convert the bytes of an i3dm tile to a GLB.
+pub fn i3dm_to_glb(bytes: &[u8], instancing: Instancing) -> Result<Vec<u8>, String> {
+    I3dm::from_bytes(bytes)?.to_glb(instancing)
+}
+
+// Find every node with a mesh below `index` along with its global transform.
+fn collect_mesh_nodes(json: &Value, index: usize, parent: Mat4, out: &mut Vec<(u64, Mat4)>) {
+    let Some(node) = json["nodes"].get(index) else {
+        return;
+    };
+    let global = multiply(&parent, &local_matrix(node));
+    if let Some(mesh) = node["mesh"].as_u64() {
+        out.push((mesh, global));
+    }
+    for child in node["children"].as_array().into_iter().flatten().filter_map(Value::as_u64) {
+        collect_mesh_nodes(json, child as usize, global, out);
+    }
+}
+
+// The instance transform expressed in the glTF's Y-up frame.
+//
+// Clients rotate the glTF from Y-up to Z-up before placing it in the tile, so
+// the Z-up instance transform is conjugated with that rotation.
+fn gltf_instance_matrix(instance: &Instance) -> Mat4 {
+    let [r, u, f] = instance.rotation;
+    let [sx, sy, sz] = instance.scale;
+    let t = instance.translation;
+    let z_up: Mat4 = [
+        r[0] * sx, r[1] * sx, r[2] * sx, 0.0,
+        u[0] * sy, u[1] * sy, u[2] * sy, 0.0,
+        f[0] * sz, f[1] * sz, f[2] * sz, 0.0,
+        t[0], t[1], t[2], 1.0,
+    ];
+    let y_up_to_z_up: Mat4 = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+    let z_up_to_y_up: Mat4 = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+    multiply(&z_up_to_y_up, &multiply(&z_up, &y_up_to_z_up))
+}
+
+const IDENTITY: Mat4 = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+
+fn local_matrix(node: &Value) -> Mat4 {
+    if let Some(matrix) = node["matrix"].as_array().filter(|m| m.len() == 16) {
+        let mut m = IDENTITY;
+        for (i, v) in matrix.iter().enumerate() {
+            m[i] = v.as_f64().unwrap_or(m[i]);
+        }
+        return m;
+    }
+    let get = |key: &str, default: &[f64]| -> Vec<f64> {
+        match node[key].as_array() {
+            Some(values) if values.len() == default.len() => values.iter().map(|v| v.as_f64().unwrap_or(0.0)).collect(),
+            _ => default.to_vec(),
+        }
+    };
+    let t = get("translation", &[0.0, 0.0, 0.0]);
+    let q = get("rotation", &[0.0, 0.0, 0.0, 1.0]);
+    let s = get("scale", &[1.0, 1.0, 1.0]);
+    let (x, y, z, w) = (q[0], q[1], q[2], q[3]);
+    [
+        (1.0 - 2.0 * (y * y + z * z)) * s[0], 2.0 * (x * y + z * w) * s[0], 2.0 * (x * z - y * w) * s[0], 0.0,
+        2.0 * (x * y - z * w) * s[1], (1.0 - 2.0 * (x * x + z * z)) * s[1], 2.0 * (y * z + x * w) * s[1], 0.0,
+        2.0 * (x * z + y * w) * s[2], 2.0 * (y * z - x * w) * s[2], (1.0 - 2.0 * (x * x + y * y)) * s[2], 0.0,
+        t[0], t[1], t[2], 1.0,
+    ]
+}
+
+fn multiply(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut m = [0.0; 16];
+    for col in 0..4 {
+        for row in 0..4 {
+            m[col * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[col * 4 + k]).sum();
+        }
+    }
+    m
+}
+
+// Split transforms into flat translation, rotation quaternion and scale arrays.
+fn decompose_all(transforms: &[Mat4]) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+    let (mut translations, mut rotations, mut scales) = (Vec::new(), Vec::new(), Vec::new());
+    for m in transforms {
+        let mut columns = [[m[0], m[1], m[2]], [m[4], m[5], m[6]], [m[8], m[9], m[10]]];
+        let mut scale = columns.map(length);
+        if dot(cross(columns[0], columns[1]), columns[2]) < 0.0 {
+            scale[0] = -scale[0];
+        }
+        for (column, s) in columns.iter_mut().zip(scale) {
+            *column = column.map(|v| if s != 0.0 { v / s } else { v });
+        }
+        translations.extend([m[12], m[13], m[14]]);
+        rotations.extend(quaternion(columns));
+        scales.extend(scale);
+    }
+    (translations, rotations, scales)
+}
+
+// Quaternion (x, y, z, w) of a rotation matrix given by its columns.
+fn quaternion(c: [[f64; 3]; 3]) -> [f64; 4] {
+    let r = |row: usize, col: usize| c[col][row];
+    let trace = r(0, 0) + r(1, 1) + r(2, 2);
+    let q = if trace > 0.0 {
+        let s = (trace + 1.0).sqrt() * 2.0;
+        [(r(2, 1) - r(1, 2)) / s, (r(0, 2) - r(2, 0)) / s, (r(1, 0) - r(0, 1)) / s, 0.25 * s]
+    } else if r(0, 0) > r(1, 1) && r(0, 0) > r(2, 2) {
+        let s = (1.0 + r(0, 0) - r(1, 1) - r(2, 2)).sqrt() * 2.0;
+        [0.25 * s, (r(0, 1) + r(1, 0)) / s, (r(0, 2) + r(2, 0)) / s, (r(2, 1) - r(1, 2)) / s]
+    } else if r(1, 1) > r(2, 2) {
+        let s = (1.0 + r(1, 1) - r(0, 0) - r(2, 2)).sqrt() * 2.0;
+        [(r(0, 1) + r(1, 0)) / s, 0.25 * s, (r(1, 2) + r(2, 1)) / s, (r(0, 2) - r(2, 0)) / s]
+    } else {
+        let s = (1.0 + r(2, 2) - r(0, 0) - r(1, 1)).sqrt() * 2.0;
+        [(r(0, 2) + r(2, 0)) / s, (r(1, 2) + r(2, 1)) / s, 0.25 * s, (r(1, 0) - r(0, 1)) / s]
+    };
+    let norm = q.iter().map(|v| v * v).sum::<f64>().sqrt();
+    q.map(|v| v / norm)
+}
+
+// Columns east, north and up of the local frame at an ECEF position.
+fn east_north_up_frame(p: [f64; 3]) -> [[f64; 3]; 3] {
+    if length(p) < 1e-6 {
+        return [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    }
+    let a2 = WGS84_RADIUS_EQUATOR * WGS84_RADIUS_EQUATOR;
+    let b2 = WGS84_RADIUS_POLAR * WGS84_RADIUS_POLAR;
+    let up = normalize([p[0] / a2, p[1] / a2, p[2] / b2]);
+    let east = if p[0].abs() < 1e-6 && p[1].abs() < 1e-6 { [0.0, 1.0, 0.0] } else { normalize([-p[1], p[0], 0.0]) };
+    [east, cross(up, east), up]
+}
+
+// Decode oct-encoded unit vectors with 16 bits per component.
+fn oct_decode_all(encoded: &[u16]) -> Vec<f64> {
+    encoded.chunks_exact(2).flat_map(|e| {
+        let mut x = e[0] as f64 / 65535.0 * 2.0 - 1.0;
+        let mut y = e[1] as f64 / 65535.0 * 2.0 - 1.0;
+        let z = 1.0 - x.abs() - y.abs();
+        if z < 0.0 {
+            let (old_x, old_y) = (x, y);
+            x = (1.0 - old_y.abs()) * old_x.signum();
+            y = (1.0 - old_x.abs()) * old_y.signum();
+        }
+        normalize([x, y, z])
+    }).collect()
+}
+
+fn to_f32(values: &[f64]) -> Vec<f32> {
+    values.iter().map(|v| *v as f32).collect()
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn length(v: [f64; 3]) -> f64 {
+    dot(v, v).sqrt()
+}
+
+fn normalize(v: [f64; 3]) -> [f64; 3] {
+    let l = length(v);
+    if l == 0.0 { v } else { v.map(|c| c / l) }
+}
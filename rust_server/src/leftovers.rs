@@ -118,7 +118,7 @@
 
 
 /////// DOWNLOAD EVERYTHING FUNCTIONS ////////
-// const TILESET_URL_FULL: &str = "https://waapi.webatlas.no/3d-tiles/tileserver.fcgi/tileset.json?api_key=DB124B20-9D21-4647-B65A-16C651553E48";
+// const TILESET_URL_FULL: &str = "https://waapi.webatlas.no/3d-tiles/tileserver.fcgi/tileset.json";
 // fn fetch_all_tilesets() {
 //     let result = request_tileset(TILESET_URL_FULL);
 //     fs::write(PATH_1_0.to_string() + "/tileset.json", &result).expect("Unable to write file");
@@ -1,11 +1,53 @@
+pub mod b3dm;
+pub mod cache;
+pub mod cmpt;
+pub mod config;
+pub mod conditional;
+pub mod convert;
+pub mod cors;
+pub mod encoding;
+pub mod eviction;
+pub mod freshness;
+pub mod glb;
+pub mod http;
+pub mod i3dm;
+pub mod migrate;
+pub mod pnts;
+pub mod retention;
+pub mod rewrite;
+pub mod single_flight;
+pub mod source;
+pub mod store;
+pub mod table;
+pub mod tileset;
+pub mod upgrade;
+pub mod upstream;
+
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
 };
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: Option<mpsc::Sender<Job>>,
+    backlog: Backlog,
+}
+
+/// The jobs of a [`ThreadPool`] that are waiting for a free worker.
+///
+/// Long-running jobs, e.g. idle persistent connections, use it to give way to waiting ones.
+#[derive(Clone, Debug, Default)]
+pub struct Backlog(Arc<AtomicUsize>);
+
+impl Backlog {
+    pub fn is_empty(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -24,25 +66,32 @@ impl ThreadPool {
         let (sender, receiver) = mpsc::channel();
 
         let receiver = Arc::new(Mutex::new(receiver));
+        let backlog = Backlog::default();
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), backlog.clone()));
         }
 
         ThreadPool {
             workers,
             sender: Some(sender),
+            backlog,
         }
     }
 
+    pub fn backlog(&self) -> Backlog {
+        self.backlog.clone()
+    }
+
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
 
+        self.backlog.0.fetch_add(1, Ordering::Relaxed);
         self.sender.as_ref().unwrap().send(job).unwrap();
     }
 }
@@ -67,13 +116,17 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, backlog: Backlog) -> Worker {
         let thread = thread::spawn(move || loop {
             let message = receiver.lock().unwrap().recv();
 
             match message {
                 Ok(job) => {
-                    job(); //println!("Worker {id} got a job; executing.");
+                    backlog.0.fetch_sub(1, Ordering::Relaxed);
+                    // A panicking job, e.g. on a malformed tile, mustn't take the worker down with it
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        println!("Worker {id} recovered from a panicking job");
+                    }
                 }
                 Err(_) => {
                     println!("Worker {id} disconnected; shutting down.");
@@ -1,266 +1,637 @@
 use std::{
-    env, ffi::OsStr, fs, io::{prelude::*, Read}, net::{TcpListener, TcpStream}, path::{Path, PathBuf}, process::{Command, Stdio}, str::from_utf8
+    collections::HashSet, env, fs, io::{BufRead, BufReader, ErrorKind}, net::{TcpListener, TcpStream}, path::PathBuf, sync::{Arc, LazyLock, Mutex, OnceLock}, process, thread, time::{Duration, Instant, SystemTime}
 };
 
-use regex::Regex;
 use reqwest::blocking::Client;
 use std::fs::File;
-use tileset_conversion_server::ThreadPool;
-use num_cpus;
-
-const TILESERVER_URL: &str = "https://waapi.webatlas.no/3d-tiles/tileserver.fcgi/";
-const API_KEY: &str = "?api_key=DB124B20-9D21-4647-B65A-16C651553E48";
+use tileset_conversion_server::{
+    cache, conditional, config::Config, convert, cors::Cors, encoding::{self, Effort, Encoding}, eviction::CacheManager, freshness::{self, Revalidation}, glb::Glb, http::{self, ByteRange, Request, RequestError, Response}, i3dm::Instancing, migrate, retention::{MemoryCache, MemoryGlb, Retention}, rewrite, single_flight::SingleFlight, source::Source, store::{Entry, Key, Kind, Store}, tileset::{self, Tileset}, upgrade, upstream::{self, FetchError, Validators}, Backlog, ThreadPool
+};
 
-const PATH_TILESET_DIR: &str = "tileset_cache/tilesets";
-const PATH_B3DM_DIR: &str = "tileset_cache/b3dms";
-const PATH_GLB_DIR: &str = "tileset_cache/glbs";
+// How often an idle persistent connection checks whether other connections are waiting for its worker
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Tells clients why content they asked for isn't served
+const CACHE_MISS_REASON: &str = "X-Cache-Miss-Reason";
+// The variant of a tileset holding the JSON this server serves for it
+const SERVED_VARIANT: &str = "served";
+
+// Workers asking for the same uncached content wait for the one already fetching or converting it
+static TILESET_FETCHES: LazyLock<SingleFlight<Result<String, FetchError>>> = LazyLock::new(SingleFlight::new);
+static MODEL_FETCHES: LazyLock<SingleFlight<Result<Entry, FetchError>>> = LazyLock::new(SingleFlight::new);
+static CONVERSIONS: LazyLock<SingleFlight<Result<Entry, ModelError>>> = LazyLock::new(SingleFlight::new);
+static MEMORY_CONVERSIONS: LazyLock<SingleFlight<Result<MemoryGlb, ModelError>>> = LazyLock::new(SingleFlight::new);
+static REVALIDATIONS: LazyLock<SingleFlight<Option<String>>> = LazyLock::new(SingleFlight::new);
+// Tilesets revalidated on a background thread, so a burst of requests for a stale one starts a single thread
+static BACKGROUND_REVALIDATIONS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+// The cached content and its index, set once the flat layout is migrated
+static STORE: OnceLock<Store> = OnceLock::new();
+static CACHE: OnceLock<CacheManager> = OnceLock::new();
+// GLBs converted on demand when only source models are kept on disk
+static GLB_MEMORY: OnceLock<MemoryCache> = OnceLock::new();
 
 fn main() {    
-    // Ensure the required directories exists
-    fs::create_dir_all(PATH_TILESET_DIR).expect(format!("Couldn't create required dir {}", PATH_TILESET_DIR).as_str());
-    fs::create_dir_all(PATH_B3DM_DIR).expect(format!("Couldn't create required dir {}", PATH_B3DM_DIR).as_str());
-    fs::create_dir_all(PATH_GLB_DIR).expect(format!("Couldn't create required dir {}", PATH_GLB_DIR).as_str());
-    
-    let thread_count = num_cpus::get();
-    let pool = ThreadPool::new(thread_count);
-    let hostname = format!("{}:7878", get_hostname()); // e.g 192.168.1.2:7878
-    let listener = TcpListener::bind(&hostname).expect("Failed to bind TcpListener");
+    let config = match Config::load(env::args().skip(1)) {
+        Ok(config) => Arc::new(config),
+        Err(e) => { println!("{}", e); process::exit(2); }
+    };
 
-    println!("Started 3DTiles Conversion Server with {} threads listening on {}...", thread_count, &hostname);
+    let removed = cache::remove_orphaned_temp_files(&config.cache_dir);
+    if removed > 0 {
+        println!("Removed {} temp files left behind in {}", removed, config.cache_dir);
+    }
 
+    let store = match migrate::open_store(&config) {
+        Ok(store) => STORE.get_or_init(|| store),
+        Err(e) => { println!("{}", e); process::exit(2); }
+    };
+    CACHE.get_or_init(|| config.cache_manager()).enforce_all(store);
+    println!("Cache holds {} bytes of tilesets, {} bytes of models and {} bytes of glbs",
+        store.size(Kind::Tilesets), store.size(Kind::Models), store.size(Kind::Glbs));
+    
+    let addresses = config.listen_addresses().unwrap_or_else(|e| { println!("{}", e); process::exit(2); });
+    let listeners: Vec<TcpListener> = addresses
+        .iter()
+        .map(|address| TcpListener::bind(address).unwrap_or_else(|e| { println!("Failed to bind TcpListener to {}: {}", address, e); process::exit(2); }))
+        .collect();
+    // Port 0 picks a free port, so log the addresses that were actually bound
+    let bound: Vec<String> = listeners.iter().filter_map(|l| l.local_addr().ok()).map(|a| a.to_string()).collect();
+
+    let pool = ThreadPool::new(config.threads);
+    println!("Started 3DTiles Conversion Server with {} threads listening on {}...", config.threads, bound.join(", "));
+
+    // Every listener accepts on its own thread, and connections from all of them share the pool
+    thread::scope(|scope| {
+        for listener in &listeners {
+            let (pool, config) = (&pool, &config);
+            scope.spawn(move || accept_connections(listener, pool, config));
+        }
+    });
+    println!("Shutting down server.");
+}
+
+fn accept_connections(listener: &TcpListener, pool: &ThreadPool, config: &Arc<Config>) {
     for stream in listener.incoming() {
-        let stream = stream.expect("Failed to unwrap TcpStream");
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => { println!("Error when accepting a connection: {}", e); continue; }
+        };
         let client = reqwest::blocking::Client::new();
-        pool.execute(|| {
-            handle_connection(stream, client);
+        let config = Arc::clone(config);
+        let backlog = pool.backlog();
+        pool.execute(move || {
+            handle_connection(stream, client, &config, &backlog);
         });
     }
-    println!("Shutting down server.");
 }
 
-fn handle_connection(mut stream: TcpStream, client: Client) {
-    let mut buffer = [0; 1024];
-    if let Err(e) = stream.read(&mut buffer){
-        println!("Error when reading request header from stream: {}", e); return;
-    };
+fn handle_connection(stream: TcpStream, client: Client, config: &Config, backlog: &Backlog) {
+    let cors = Cors::new(&config.cors_allowed_origins);
+    let keep_alive_timeout = Duration::from_secs(config.keep_alive_timeout_secs);
 
-    let request_path = match from_utf8(&buffer) {
-        Ok(v) => v,
-        Err(e) => {println!("Failed to unwrap request from Unity: {:#?}", e); return; },
-    };
-
-    let re = Regex::new(r"(?<tileset>[0-9]*tileset.json)|(?<model>[0-9]+model.cmpt|[0-9]+model.b3dm|[0-9]+model)").unwrap();
-    match re.captures(request_path) {
-        Some(caps) => {
-            if caps.name("tileset").is_some() {stream_tileset(&stream, &client, &caps["tileset"])}
-            else {stream_model(&stream, &client, &caps["model"])}
+    let mut reader = BufReader::new(&stream);
+    for served in 1..=config.max_requests_per_connection {
+        // Persistent connections are closed after being idle for a while, so they don't tie up a worker forever
+        if served > 1 && !wait_for_next_request(&stream, &mut reader, keep_alive_timeout, backlog) {
+            return;
         }
-        None => not_found_response(&stream),
-    };
-}
-
-/////// RESPONSE FUNCTIONS ////////
-fn stream_tileset(mut stream: &TcpStream, client: &Client, filename: &str) {
-    let tileset_path = PATH_TILESET_DIR.to_string() + "/" + filename;
-    let contents: String = 
-        if !Path::new(&tileset_path).exists() {
-            println!("{} is not available locally. Fetching it.", filename);
-            let url = TILESERVER_URL.to_string() + filename + API_KEY; 
-            let Ok(c) = request_and_cache_tileset(client, &url, filename) else {
-                println!("Unable to fetch file {}", &tileset_path);
-                not_found_response(&stream);
+        if let Err(e) = stream.set_read_timeout(Some(keep_alive_timeout)) {
+            println!("Error when setting the idle timeout: {}", e); return;
+        };
+        let request = match http::read_request(&mut reader) {
+            Ok(request) => request,
+            Err(RequestError::Malformed(e)) => {
+                println!("Received a malformed request: {}", e);
+                let _ = respond(&stream, config, Response::new(400), false, false);
                 return;
-            }; 
-            c
+            }
+            Err(RequestError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return,
+            Err(RequestError::Io(e)) => { println!("Error when reading request from stream: {}", e); return; },
+            Err(RequestError::Closed) => return,
+        };
+
+        let keep_alive = wants_keep_alive(&request) && served < config.max_requests_per_connection;
+        // Browsers send a preflight before cross-origin requests with headers such as Range
+        let response = if request.method == "OPTIONS" {
+            cors.preflight(&request)
         } else {
-            let Ok(c) = fs::read_to_string(&tileset_path) else {
-                println!("Unable to read file {}", &tileset_path);
-                not_found_response(&stream);
-                return;
-            }; 
-            c
+            cors.apply(&request, route_request(&request, &client, config))
         };
+        if let Err(e) = respond(&stream, config, response, keep_alive, request.method == "HEAD") {
+            println!("Error when writing response for {}: {}", request.path, e); return;
+        }
+        if !keep_alive {
+            return;
+        }
+    }
+}
 
-    let status_line = "HTTP/1.1 200 OK";
-    let length: usize = contents.len();
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+// Whether the client sent more before the idle timeout. Gives up early when other
+// connections are waiting, as a worker serves a single connection at a time.
+fn wait_for_next_request(stream: &TcpStream, reader: &mut BufReader<&TcpStream>, timeout: Duration, backlog: &Backlog) -> bool {
+    if let Err(e) = stream.set_read_timeout(Some(IDLE_POLL_INTERVAL)) {
+        println!("Error when setting the idle timeout: {}", e); return false;
+    }
+    let idle_since = Instant::now();
+    loop {
+        match reader.fill_buf() {
+            // Pipelined requests are already buffered
+            Ok(buffered) => return !buffered.is_empty(),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if !backlog.is_empty() || idle_since.elapsed() >= timeout {
+                    return false;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+}
 
-    if let Err(e) = stream.write_all(response.as_bytes()) {
-        println!("Error when streaming tileset: {}", e);
-        not_found_response(&stream);
-    }; 
+// HTTP/1.1 connections are persistent unless the client opts out, HTTP/1.0 ones only if it opts in
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.header("Connection").unwrap_or("");
+    let has_token = |token: &str| connection.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+    if request.version == "HTTP/1.0" { has_token("keep-alive") } else { !has_token("close") }
+}
 
-    if let Err(e) = stream.flush() {
-        println!("Error when flushing: {}", e);
-    };
-    println!("Streamed tileset {:#?}", filename);
+fn respond(mut stream: &TcpStream, config: &Config, mut response: Response, keep_alive: bool, head_only: bool) -> std::io::Result<()> {
+    if keep_alive {
+        let keep_alive = format!("timeout={}, max={}", config.keep_alive_timeout_secs, config.max_requests_per_connection);
+        response = response.with_header("Connection", "keep-alive").with_header("Keep-Alive", &keep_alive);
+    } else {
+        response = response.with_header("Connection", "close");
+    }
+    if head_only {
+        response.write_head_to(&mut stream)
+    } else {
+        response.write_to(&mut stream)
+    }
 }
 
-fn stream_model(mut stream: &TcpStream, client: &Client, filename: &str) {
-    let filename_stemmed = Path::new(filename).file_stem().unwrap().to_str().unwrap();
-    let path_b3dm = PATH_B3DM_DIR.to_string() + "/" + filename_stemmed + ".b3dm";
-    let path_glb = PATH_GLB_DIR.to_string() + "/" + filename_stemmed + ".glb";
-    if !Path::new(&path_glb).exists() {
-        if !Path::new(&path_b3dm).exists() {
-            println!("{} is not available locally. Fetching it.", filename);
-            let url = TILESERVER_URL.to_string() + filename + API_KEY;
-            let was_success = request_and_cache_binary_model_file(client, &url, &path_b3dm);
-            if !was_success {
-                not_found_response(&stream);
-                return; 
-            }
-        }   
-        // Convert the model file to a glb file and return it
-        if filename.contains("cmpt") { convert_cmpt_to_glb(filename_stemmed); } 
-        else { convert_b3dm_to_glb(filename, filename_stemmed); }   
+fn route_request(request: &Request, client: &Client, config: &Config) -> Response {
+    // HEAD is answered like GET, and the body is left out when it's written
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::new(405).with_header("Allow", "GET, HEAD, OPTIONS");
     }
 
-    //MIME type: model/gltf-binary or application/octet-stream
-    let Ok(contents) = fs::read(&path_glb) else {
-        println!("Unable to read file {}", &path_glb);
-        not_found_response(&stream);
-        return;
+    // Content is cached under the same relative paths as it has upstream
+    let path = request.path.trim_start_matches('/');
+    if path.is_empty() || path.split('/').any(|segment| segment.is_empty() || segment == "." || segment == "..") {
+        return not_found_response();
+    }
+    // e.g. buildings/tileset.json is tileset.json of the buildings source
+    let Some((source, path)) = config.route(path) else {
+        return not_found_response();
     };
 
-    let response = format!("HTTP/1.0 200 OK\r\nContent-Type: model/gltf-binary\r\nContent-Length: {}\r\n\r\n", contents.len());
-    
-    if let Err(e) = stream.write_all(response.as_bytes()) {
-        println!("Error when streaming model: {}", e); 
-        not_found_response(&stream);
-        return;
-    }; 
-
-    if let Err(e) = stream.write_all(&contents) {
-        println!("Error when streaming model: {}", e); 
-        not_found_response(&stream);
-        return;
+    // Served tilesets reference models by their converted name, e.g. 123model.b3dm.glb,
+    // and the query is forwarded, e.g. 123model.b3dm.glb?v=2 is fetched as 123model.b3dm?v=2
+    let upstream_path = rewrite::upstream_path(path, request);
+    if tileset::is_tileset_path(path) {
+        return stream_tileset(request, client, config, &source, &upstream_path);
+    }
+
+    // e.g. 123model.cmpt?tile=2 requests the third inner tile of a composite instead of all of them merged
+    let inner_tile = match request.query_param("tile").map(str::parse) {
+        None => None,
+        Some(Ok(index)) => Some(index),
+        Some(Err(_)) => return Response::new(400),
     };
+    // e.g. 123model.i3dm?instancing=baked for clients without EXT_mesh_gpu_instancing
+    let mut options = convert::Options::default();
+    if request.query_param("instancing") == Some("baked") { options.instancing = Instancing::Baked; }
 
-    if let Err(_) = stream.flush() {
-        println!("Error when flushing"); return;
-    }; 
-    
-    println!("Streamed model {:#?}", filename);
+    stream_model(request, client, config, &source, &upstream_path, inner_tile, &options)
 }
 
-/////// STREAM REQUEST FUNCTIONS ////////
-fn request_and_cache_tileset(client: &Client, req_url: &str, file_name: &str) -> Result<String, String> {    
-    let Ok(mut response) = client.get(req_url).send() else {
-        return Err(format!("Failed to fetch from: {}", req_url));
+/////// RESPONSE FUNCTIONS ////////
+fn stream_tileset(request: &Request, client: &Client, config: &Config, source: &Source, filename: &str) -> Response {
+    let key = source.key(filename, "");
+    // A cached tileset is only read when it isn't served from the cache of prepared ones
+    let (cached, contents) = match store().get(&key) {
+        // Offline a stale tileset is still the best there is
+        Some(entry) if !config.offline && freshness::is_stale(&entry, Duration::from_secs(config.tileset_max_age_secs)) => {
+            match revalidate_stale_tileset(client, config, source, filename) {
+                Some(changed) => (None, Some(changed)),
+                None => (Some(entry), None),
+            }
+        }
+        Some(entry) => (Some(entry), None),
+        None if config.offline => return offline_response(config, source, filename, &key, Kind::Tilesets),
+        None => {
+            let fetched = TILESET_FETCHES.run(&key.id(), || {
+                // Another worker may have cached it since we looked
+                if let Some((c, _)) = read_cached_tileset(&key) {
+                    return Ok(c);
+                }
+                println!("{} is not available locally. Fetching it.", filename);
+                request_and_cache_tileset(client, config, source, filename)
+            });
+            match fetched {
+                Ok(c) => (None, Some(c)),
+                Err(FetchError::Offline(_)) => return offline_response(config, source, filename, &key, Kind::Tilesets),
+                Err(e) => {
+                    println!("Unable to fetch file {}: {}", source.url_of(filename), e);
+                    return Response::new(e.status());
+                }
+            }
+        }
     };
 
-    let mut body = String::new();
-    if let Err(e) = response.read_to_string(&mut body) {
-        return Err(format!("Error when reading response to string: {}", e));
+    // Serve the tileset as 3D Tiles 1.1 referencing the GLBs converted by this server.
+    // The ETag covers the served JSON, which also depends on how this server rewrites it.
+    let (contents, served_etag) = match served_tileset(config, source, filename, &key, cached.as_ref(), contents) {
+        Ok(served) => served,
+        Err(e) => {
+            println!("Unable to serve tileset {}: {}", filename, e);
+            return Response::new(500);
+        }
     };
 
-    if let Err(e) = fs::write(format!("{}/{}", PATH_TILESET_DIR, file_name), &body) {
-        return Err(format!("Error when writing tileset to file: {}", e));
+    let encoding = if contents.len() < encoding::MIN_COMPRESSED_LENGTH { Encoding::Identity } else { encoding::negotiate(request.header("Accept-Encoding")) };
+
+    let etag = encoding::encoded_etag(&served_etag, encoding);
+    // The blob is written when the content changes, while revalidating only updates the index
+    let last_modified = store().get(&key).and_then(|entry| fs::metadata(entry.path).and_then(|m| m.modified()).ok());
+    let cache_control = &config.cache_control_tilesets;
+    if conditional::is_not_modified(request, &etag, last_modified) {
+        return with_validators(Response::new(304), &etag, last_modified, cache_control).with_vary("Accept-Encoding");
+    }
+
+    let body = match encode_tileset(config, &key, &served_etag, contents, encoding) {
+        Ok(body) => body,
+        Err(e) => {
+            println!("Unable to encode tileset {}: {}", filename, e);
+            return Response::new(500);
+        }
     };
 
-    return Ok(body);
+    println!("Streaming tileset {:#?} ({})", filename, encoding.name());
+    let response = with_validators(Response::new(200), &etag, last_modified, cache_control)
+        .with_header("Content-Type", "application/json")
+        .with_vary("Accept-Encoding");
+    match encoding {
+        Encoding::Identity => response.with_body(body),
+        _ => response.with_header("Content-Encoding", encoding.name()).with_body(body),
+    }
 }
 
-fn request_and_cache_binary_model_file(client: &Client, req_url: &str, target_file_path: &str) -> bool {
-    let Ok(response) = client.get(req_url).send() else {
-        println!("Failed to fetch from: {}", req_url);
-        return false;
+fn stream_model(request: &Request, client: &Client, config: &Config, source: &Source, filename: &str, inner_tile: Option<usize>, options: &convert::Options) -> Response {
+    let model_key = source.key(filename, "");
+    let glb_key = model_key.variant(&convert::glb_variant(inner_tile, options));
+    // A cached source model can still be converted offline
+    if config.offline && store().get(&glb_key).is_none() && store().get(&model_key).is_none() {
+        return offline_response(config, source, filename, &model_key, Kind::Models);
+    }
+    if config.source_retention == Retention::Source {
+        return stream_model_from_memory(request, client, config, source, filename, &model_key, &glb_key, inner_tile, options);
+    }
+    let store = store();
+    let entry = match store.get(&glb_key) {
+        Some(entry) => entry,
+        None => {
+            // Variants of the same model share the fetch, but each has its own conversion
+            let converted = CONVERSIONS.run(&glb_key.id(), || {
+                if let Some(entry) = store.get(&glb_key) {
+                    return Ok(entry);
+                }
+                let (model, temp) = convert_model(client, config, source, filename, &model_key, inner_tile, options)?;
+                let params = convert::glb_params(config.converter, &model.blob);
+                let entry = store.put_file(&glb_key, Kind::Glbs, "model/gltf-binary", &temp, &Validators::default(), &params).map_err(|e| {
+                    println!("Unable to cache the conversion of {}: {}", filename, e);
+                    ModelError::Status(500)
+                })?;
+                if config.source_retention == Retention::Glb {
+                    store.remove(&model_key);
+                }
+                enforce_quota(Kind::Glbs, &glb_key);
+                Ok(entry)
+            });
+            match converted {
+                Ok(entry) => entry,
+                Err(e) => return model_error_response(config, source, filename, &model_key, e),
+            }
+        }
     };
 
-    let mut file = match File::create(Path::new(&target_file_path)) {
-        Ok(file) => file,
-        Err(_) => return false,
+    //MIME type: model/gltf-binary or application/octet-stream
+    let Ok(file) = File::open(&entry.path) else {
+        println!("Unable to read file {}", entry.path.display());
+        return not_found_response();
     };
+    let last_modified = file.metadata().and_then(|m| m.modified()).ok();
+    model_response(request, config, filename, ModelBody::File(file), entry.size, &entry.etag(), last_modified)
+}
 
-    let Ok(content) = response.bytes() else {
-        println!("Failed to unwrap bytes from the response:");
-        return false;
+// With source_retention = "source" GLBs are only kept in memory, and converted again once they're evicted or their source changed
+#[allow(clippy::too_many_arguments)]
+fn stream_model_from_memory(request: &Request, client: &Client, config: &Config, source: &Source, filename: &str, model_key: &Key, glb_key: &Key, inner_tile: Option<usize>, options: &convert::Options) -> Response {
+    let store = store();
+    let memory = GLB_MEMORY.get_or_init(|| MemoryCache::new(config.glb_memory_cache_mb.saturating_mul(1024 * 1024)));
+    let is_current = |glb: &MemoryGlb| store.get(model_key).is_some_and(|model| model.blob == glb.source_blob);
+    let glb = match memory.get(&glb_key.id()).filter(is_current) {
+        Some(glb) => glb,
+        None => {
+            let converted = MEMORY_CONVERSIONS.run(&glb_key.id(), || {
+                if let Some(glb) = memory.get(&glb_key.id()).filter(is_current) {
+                    return Ok(glb);
+                }
+                // The converters write files, so the GLB goes through a temp file that's removed right away
+                let (model, temp) = convert_model(client, config, source, filename, model_key, inner_tile, options)?;
+                let bytes = fs::read(&temp);
+                let _ = fs::remove_file(&temp);
+                let bytes = bytes.map_err(|e| { println!("Unable to read converted {}: {}", filename, e); ModelError::Status(500) })?;
+                let last_modified = fs::metadata(&model.path).and_then(|m| m.modified()).ok();
+                let glb = MemoryGlb { etag: conditional::etag_for_bytes(&bytes), bytes: Arc::new(bytes), source_blob: model.blob, last_modified };
+                memory.insert(&glb_key.id(), glb.clone());
+                Ok(glb)
+            });
+            match converted {
+                Ok(glb) => glb,
+                Err(e) => return model_error_response(config, source, filename, model_key, e),
+            }
+        }
     };
+    let length = glb.bytes.len() as u64;
+    model_response(request, config, filename, ModelBody::Memory(glb.bytes), length, &glb.etag, glb.last_modified)
+}
 
-    if let Err(e) = file.write_all(&content) {
-        println!("Error when writing model to file: {}", e);
-        return false;
-    };
+// Fetches the source model unless it's cached, and converts it to a GLB in a temp file of the store
+fn convert_model(client: &Client, config: &Config, source: &Source, filename: &str, model_key: &Key, inner_tile: Option<usize>, options: &convert::Options) -> Result<(Entry, PathBuf), ModelError> {
+    let store = store();
+    for attempt in 0..2 {
+        let fetched = MODEL_FETCHES.run(&model_key.id(), || {
+            if let Some(entry) = store.get(model_key) {
+                return Ok(entry);
+            }
+            println!("{} is not available locally. Fetching it.", filename);
+            request_and_cache_binary_model_file(client, config, source, filename, model_key)
+        });
+        let model = match fetched {
+            Ok(model) => model,
+            // It was evicted since it was looked up
+            Err(FetchError::Offline(_)) => return Err(ModelError::Offline),
+            Err(e) => {
+                println!("Unable to fetch file {}: {}", source.url_of(filename), e);
+                return Err(ModelError::Status(e.status()));
+            }
+        };
+        // Convert the model file to a glb file
+        let temp = store.temp_path();
+        match convert::convert_file(&model.path.to_string_lossy(), &temp.to_string_lossy(), inner_tile, options, config.converter) {
+            Ok(()) => return Ok((model, temp)),
+            // The conversion of another variant may have removed the source in between, or it was evicted
+            Err(_) if attempt == 0 && !model.path.exists() => continue,
+            Err(e) => {
+                println!("Unable to convert {}: {}", filename, e);
+                return Err(ModelError::Status(404));
+            }
+        }
+    }
+    Err(ModelError::Status(404))
+}
 
-    return true;
+// Why a model can't be served
+#[derive(Clone, Copy, Debug)]
+enum ModelError {
+    Status(u16),
+    /// It isn't cached, and upstream isn't contacted while offline.
+    Offline,
 }
 
-fn not_found_response(mut stream: &TcpStream) {
-    // let contents = fs::read_to_string(filename).unwrap();
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        "HTTP/1.1 404 NOT FOUND",
-        0,
-        ""
-    );
-
-    if let Err(e) = stream.write_all(response.as_bytes()) {
-        println!("Error when responding with a 404: {}", e);
-    };
+fn model_error_response(config: &Config, source: &Source, filename: &str, model_key: &Key, e: ModelError) -> Response {
+    match e {
+        ModelError::Status(status) => Response::new(status),
+        ModelError::Offline => offline_response(config, source, filename, model_key, Kind::Models),
+    }
+}
+
+enum ModelBody {
+    File(File),
+    Memory(Arc<Vec<u8>>),
+}
+
+fn model_response(request: &Request, config: &Config, filename: &str, body: ModelBody, length: u64, etag: &str, last_modified: Option<SystemTime>) -> Response {
+    let cache_control = &config.cache_control_models;
+    if conditional::is_not_modified(request, etag, last_modified) {
+        return with_validators(Response::new(304), etag, last_modified, cache_control);
+    }
 
-    if let Err(e) = stream.flush() {
-        println!("Error when flushing: {}", e);
+    // Clients resume interrupted downloads of large tiles with a Range request.
+    // If the file changed since the part they have, If-Range makes us send all of it.
+    let range = if conditional::if_range_matches(request, etag, last_modified) { request.header("Range") } else { None };
+    let (response, start, body_length) = match http::byte_range(range, length) {
+        ByteRange::Full => (Response::new(200), 0, length),
+        ByteRange::Partial { start, end } => (Response::new(206).with_header("Content-Range", &format!("bytes {}-{}/{}", start, end, length)), start, end - start + 1),
+        ByteRange::Unsatisfiable => return Response::new(416).with_header("Content-Range", &format!("bytes */{}", length)),
     };
+    println!("Streaming model {:#?}", filename);
+    let response = with_validators(response, etag, last_modified, cache_control)
+        .with_header("Content-Type", "model/gltf-binary")
+        .with_header("Accept-Ranges", "bytes");
+    match body {
+        ModelBody::File(file) => response.with_file(file, start, body_length),
+        ModelBody::Memory(bytes) => response.with_body(bytes[start as usize..(start + body_length) as usize].to_vec()),
+    }
 }
 
-/////// COMMANDLINE FUNCTIONS ////////
-fn get_hostname() -> String {
-    let output = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(["/C", "hostname -I"])
-            .stdout(Stdio::piped())
-            .output()
-            .expect("Error when querying hostname")
-    } else if cfg!(target_os = "macos") {
-        Command::new("sh")
-            .arg("-c")
-            .arg("ipconfig getifaddr en0")
-            .stdout(Stdio::piped())
-            .output()
-            .expect("Error when querying hostname")
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg("hostname -I")
-            .stdout(Stdio::piped())
-            .output()
-            .expect("Error when querying hostname")
+// With precompress_tilesets the compressed JSON is cached as a variant of the
+// tileset, e.g. `br`, and reused as long as the served JSON has the same ETag.
+fn encode_tileset(config: &Config, key: &Key, served_etag: &str, contents: String, encoding: Encoding) -> Result<Vec<u8>, String> {
+    if encoding == Encoding::Identity {
+        return Ok(contents.into_bytes());
+    }
+    if !config.precompress_tilesets {
+        return encoding::compress(contents.as_bytes(), encoding, Effort::Fast);
+    }
+
+    let store = store();
+    let compressed_key = key.variant(encoding.name());
+    if let Some(compressed) = store.get(&compressed_key).filter(|entry| entry.params == served_etag) {
+        if let Ok(body) = fs::read(&compressed.path) {
+            return Ok(body);
+        }
+    }
+
+    let body = encoding::compress(contents.as_bytes(), encoding, Effort::Best)?;
+    match store.put(&compressed_key, Kind::Tilesets, "application/json", &body, &Validators::default(), served_etag) {
+        Ok(_) => enforce_quota(Kind::Tilesets, &compressed_key),
+        Err(e) => println!("Unable to cache precompressed tileset {}: {}", key.url, e),
+    }
+    Ok(body)
+}
+
+// The served JSON of a tileset and its ETag. Preparing a large tileset is
+// expensive, so the result is cached as a variant of the tileset along with
+// the blob it was prepared from, and reused as long as that's the cached one.
+fn served_tileset(config: &Config, source: &Source, filename: &str, key: &Key, cached: Option<&Entry>, contents: Option<String>) -> Result<(String, String), String> {
+    let served_key = key.variant(SERVED_VARIANT);
+    let params = cached.map(|entry| format!("source={};public_url={}", entry.blob, public_url(config, source).unwrap_or_default()));
+    if let Some(params) = &params {
+        if let Some(served) = store().get(&served_key).filter(|served| &served.params == params) {
+            if let Ok(json) = fs::read_to_string(&served.path) {
+                return Ok((json, served.etag()));
+            }
+        }
+    }
+
+    let contents = match (contents, cached) {
+        (Some(contents), _) => contents,
+        (None, Some(entry)) => fs::read_to_string(&entry.path).map_err(|e| format!("Unable to read {}: {}", entry.path.display(), e))?,
+        (None, None) => return Err("Neither fetched nor cached".to_string()),
+    };
+    let prepared = match prepare_tileset(config, source, &contents, filename) {
+        Ok(prepared) => prepared,
+        Err(e) => { println!("Serving {} without upgrading it: {}", filename, e); contents }
     };
+    // Freshly fetched tilesets are prepared again on the next request, which reads them from the cache
+    if let Some(params) = params {
+        match store().put(&served_key, Kind::Tilesets, "application/json", prepared.as_bytes(), &Validators::default(), &params) {
+            Ok(served) => {
+                enforce_quota(Kind::Tilesets, &served_key);
+                return Ok((prepared, served.etag()));
+            }
+            Err(e) => println!("Unable to cache prepared tileset {}: {}", key.url, e),
+        }
+    }
+    let etag = conditional::etag_for_bytes(prepared.as_bytes());
+    Ok((prepared, etag))
+}
 
-    let result = String::from_utf8(output.stdout).expect("Error when querying hostname");
-    return result.trim().to_string();
+fn prepare_tileset(config: &Config, source: &Source, contents: &str, filename: &str) -> Result<String, String> {
+    let mut tileset = Tileset::from_json(contents)?;
+    upgrade::upgrade_tileset(&mut tileset);
+    rewrite::rewrite_content_uris(&mut tileset, filename, &source.url, public_url(config, source).as_deref());
+    tileset.to_json()
 }
 
-fn convert_cmpt_to_glb(filename_stemmed: &str) {
-    // npx 3d-tiles-tools cmptToGlb -i ./specs/data/composite.cmpt -o ./output/extracted.glb
-    let cmd = format!("npx 3d-tiles-tools cmptToGlb -i {}/{}.b3dm -o {}/{}.glb", PATH_B3DM_DIR, &filename_stemmed, PATH_GLB_DIR, &filename_stemmed);
-    let _ = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(["/C", &cmd])
-            .output()
-            .expect("Error when upgrading tileset")
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .output()
-            .expect("Error when upgrading tileset")
+// Absolute URLs need the route of the source, relative ones stay within it anyway
+fn public_url(config: &Config, source: &Source) -> Option<String> {
+    config.public_base_url.as_ref().map(|base| format!("{}/{}", base.trim_end_matches('/'), source.route_prefix()))
+}
+
+/////// STREAM REQUEST FUNCTIONS ////////
+fn request_and_cache_tileset(client: &Client, config: &Config, source: &Source, filename: &str) -> Result<String, FetchError> {    
+    if config.offline {
+        return Err(FetchError::Offline(format!("{} isn't cached", filename)));
+    }
+    let (body, validators) = upstream::fetch_tileset(client, source, filename, &config.retry_policy())?;
+
+    // The tileset can still be served, it just has to be fetched again next time
+    let key = source.key(filename, "");
+    match store().put(&key, Kind::Tilesets, "application/json", body.as_bytes(), &validators, "") {
+        Ok(_) => enforce_quota(Kind::Tilesets, &key),
+        Err(e) => println!("Error when caching tileset: {}", e),
     };
+
+    Ok(body)
+}
+
+// Returns the changed tileset when it was revalidated before answering, otherwise the stale one is served
+fn revalidate_stale_tileset(client: &Client, config: &Config, source: &Source, filename: &str) -> Option<String> {
+    if !config.revalidate_in_background {
+        return revalidate_tileset(client, config, source, filename);
+    }
+    let id = source.key(filename, "").id();
+    if !BACKGROUND_REVALIDATIONS.lock().unwrap().insert(id.clone()) {
+        return None;
+    }
+    let (client, config, source, filename) = (client.clone(), config.clone(), source.clone(), filename.to_string());
+    thread::spawn(move || {
+        let _claim = BackgroundRevalidation(id);
+        revalidate_tileset(&client, &config, &source, &filename)
+    });
+    None
+}
+
+// Releases the claim on a background revalidation when it's done, even if it panics
+struct BackgroundRevalidation(String);
+
+impl Drop for BackgroundRevalidation {
+    fn drop(&mut self) {
+        BACKGROUND_REVALIDATIONS.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+fn revalidate_tileset(client: &Client, config: &Config, source: &Source, filename: &str) -> Option<String> {
+    let key = source.key(filename, "");
+    REVALIDATIONS.run(&key.id(), || {
+        // Another worker may have revalidated it since we looked
+        let max_age = Duration::from_secs(config.tileset_max_age_secs);
+        if !store().get(&key).is_some_and(|entry| freshness::is_stale(&entry, max_age)) {
+            return None;
+        }
+        println!("{} is stale. Revalidating it.", filename);
+        match freshness::revalidate_tileset(store(), client, source, filename, &config.retry_policy()) {
+            Ok(Revalidation::Unchanged) => None,
+            Ok(Revalidation::Changed { contents, .. }) => {
+                enforce_quota(Kind::Tilesets, &key);
+                Some(contents)
+            }
+            Err(e) => {
+                println!("Unable to revalidate {}, serving the cached copy: {}", source.url_of(filename), e);
+                None
+            }
+        }
+    })
+}
+
+fn request_and_cache_binary_model_file(client: &Client, config: &Config, source: &Source, filename: &str, key: &Key) -> Result<Entry, FetchError> {
+    // It may have been evicted since it was looked up
+    if config.offline {
+        return Err(FetchError::Offline(format!("{} isn't cached", filename)));
+    }
+    let content = upstream::fetch_model(client, source, filename, &config.retry_policy())?;
+
+    let entry = store().put(key, Kind::Models, "application/octet-stream", &content, &Validators::default(), "")
+        .map_err(|e| FetchError::Cache(format!("Error when caching model: {}", e)))?;
+    enforce_quota(Kind::Models, key);
+    Ok(entry)
+}
+
+// Only checked content is cached, but reading the blob may still fail
+fn read_cached_tileset(key: &Key) -> Option<(String, Entry)> {
+    let entry = store().get(key)?;
+    let contents = fs::read_to_string(&entry.path).ok()?;
+    Some((contents, entry))
+}
+
+fn store() -> &'static Store {
+    STORE.get().expect("The store is opened at startup")
 }
 
-fn convert_b3dm_to_glb(filename: &str, filename_stemmed: &str) {
-    // npx 3d-tiles-tools convertB3dmToGlb  -i ./specs/data/composite.cmpt -o ./output/extracted.glb
-    let cmd = format!("npx 3d-tiles-tools convertB3dmToGlb -i {}/{} -o {}/{}.glb", PATH_B3DM_DIR, &filename, PATH_GLB_DIR, &filename_stemmed);
-    let _ = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(["/C", &cmd])
-            .output()
-            .expect("Error when upgrading tileset")
+// Evicts least recently used entries of a kind when it's over quota, other than the one just added
+fn enforce_quota(kind: Kind, key: &Key) {
+    if let Some(cache) = CACHE.get() {
+        cache.enforce(store(), kind, Some(key));
+    }
+}
+
+fn with_validators(response: Response, etag: &str, last_modified: Option<SystemTime>, cache_control: &str) -> Response {
+    let response = response.with_header("ETag", etag).with_header("Cache-Control", cache_control);
+    match last_modified {
+        Some(time) => response.with_header("Last-Modified", &conditional::http_date(time)),
+        None => response,
+    }
+}
+
+fn not_found_response() -> Response {
+    Response::new(404)
+}
+
+// Offline a cache miss is answered right away, and recorded so the fetcher can prefetch it once it's back online
+fn offline_response(config: &Config, source: &Source, filename: &str, key: &Key, kind: Kind) -> Response {
+    println!("{} is not available locally, and not fetched while offline", source.url_of(filename));
+    store().record_missing(key, filename, kind);
+    let response = if kind == Kind::Models && config.offline_placeholder_glb {
+        Response::new(200)
+            .with_header("Content-Type", "model/gltf-binary")
+            .with_header("Cache-Control", "no-store")
+            .with_body(Glb::placeholder().to_bytes())
     } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .output()
-            .expect("Error when upgrading tileset")
+        not_found_response()
     };
+    response.with_header(CACHE_MISS_REASON, "offline")
 }
+
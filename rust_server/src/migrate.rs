@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    b3dm, cache, cmpt,
+    config::Config,
+    convert::{self, Converter, Options},
+    glb, i3dm,
+    i3dm::Instancing,
+    pnts,
+    source::Source,
+    store::{Key, Kind, Store},
+    upstream::{self, Validators},
+};
+
+/// Open the store of the cache dir, importing the flat layout of every source into it first.
+///
+/// Both the server and the fetcher open the store like this, so whichever
+/// starts first after an upgrade does the migration.
+pub fn open_store(config: &Config) -> Result<Store, String> {
+    let store = Store::open(&config.cache_dir)?;
+    for source in config.sources() {
+        let imported = migrate_flat_layout(&store, &source, config.converter);
+        if imported > 0 {
+            println!("Imported {} cached files of {} into the cache index", imported, source.url);
+        }
+    }
+    let (entries, blobs) = store.reconcile();
+    if entries > 0 || blobs > 0 {
+        println!("Removed {} cache index entries without a blob and {} blobs without an entry", entries, blobs);
+    }
+    Ok(store)
+}
+
+/// Import the flat cache layout of a source into the store, removing the files as they're imported.
+///
+/// Before the index, tilesets were cached at their path below tilesets/,
+/// models as <stem>.b3dm below b3dms/ whatever their format, and GLBs as
+/// <stem>[_<inner tile>][_baked].glb below glbs/. Returns how many files
+/// were imported. Files that aren't valid, e.g. cached error pages, and
+/// GLBs whose model isn't known are dropped.
+pub fn migrate_flat_layout(store: &Store, source: &Source, converter: Converter) -> usize {
+    let mut imported = 0;
+
+    // e.g. tileset.json.meta holds the validators of tileset.json
+    let mut tilesets = Vec::new();
+    let mut validators = HashMap::new();
+    for (path, name) in list_files(&source.tileset_dir()) {
+        if let Some(tileset) = name.strip_suffix(".meta") {
+            let stored = fs::read(&path).ok().and_then(|json| serde_json::from_slice::<Validators>(&json).ok());
+            validators.insert(tileset.to_string(), stored.unwrap_or_default());
+        } else if !name.ends_with(".gz") && !name.ends_with(".br") {
+            tilesets.push((path.clone(), name));
+            continue;
+        }
+        // Precompressed tilesets are made again when they're served
+        let _ = fs::remove_file(path);
+    }
+    for (path, name) in tilesets {
+        let Ok(contents) = fs::read(&path) else {
+            continue;
+        };
+        if upstream::check_tileset(&contents).is_ok() {
+            let validators = validators.remove(&name).unwrap_or_default();
+            imported += import(store, &path, &source.key(&name, ""), Kind::Tilesets, "application/json", &contents, &validators, "");
+        } else {
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    // The original extension isn't in the name, but the magic tells the format.
+    // An extensionless 123model was cached like 123model.b3dm, so it's imported
+    // under both names as it's not known which one was requested.
+    let mut models = HashMap::new();
+    for (path, name) in list_files(&source.b3dm_dir()) {
+        let Ok(contents) = fs::read(&path) else {
+            continue;
+        };
+        let (Some(stem), Some(extension)) = (name.strip_suffix(".b3dm"), extension_of(&contents)) else {
+            let _ = fs::remove_file(&path);
+            continue;
+        };
+        let mut paths = vec![format!("{}.{}", stem, extension)];
+        if is_extensionless_model(stem) {
+            paths.push(stem.to_string());
+        }
+        let mut keys = Vec::new();
+        for model_path in paths {
+            let key = source.key(&model_path, "");
+            if import(store, &path, &key, Kind::Models, "application/octet-stream", &contents, &Validators::default(), "") > 0 {
+                if let Some(entry) = store.get(&key) {
+                    keys.push((key, entry.blob));
+                }
+            }
+        }
+        if !keys.is_empty() {
+            models.insert(stem.to_string(), keys);
+            imported += 1;
+        }
+    }
+
+    for (path, name) in list_files(&source.glb_dir()) {
+        let variant = name.strip_suffix(".glb").and_then(|stem| glb_model(stem, &models));
+        let (Some((model, inner_tile, options)), Ok(contents)) = (variant, fs::read(&path)) else {
+            let _ = fs::remove_file(&path);
+            continue;
+        };
+        let mut imported_glb = 0;
+        for (key, blob) in &models[model] {
+            let key = key.variant(&convert::glb_variant(inner_tile, &options));
+            imported_glb += import(store, &path, &key, Kind::Glbs, "model/gltf-binary", &contents, &Validators::default(), &convert::glb_params(converter, blob));
+        }
+        imported += imported_glb.min(1);
+    }
+
+    for dir in [source.tileset_dir(), source.b3dm_dir(), source.glb_dir(), source.cache_dir.clone()] {
+        remove_empty_dirs(Path::new(&dir));
+    }
+    imported
+}
+
+#[allow(clippy::too_many_arguments)]
+fn import(store: &Store, path: &Path, key: &Key, kind: Kind, content_type: &str, contents: &[u8], validators: &Validators, params: &str) -> usize {
+    match store.put(key, kind, content_type, contents, validators, params) {
+        Ok(_) => {
+            let _ = fs::remove_file(path);
+            1
+        }
+        Err(e) => {
+            println!("Unable to import {}: {}", path.display(), e);
+            0
+        }
+    }
+}
+
+fn extension_of(contents: &[u8]) -> Option<&'static str> {
+    let magic = contents.get(0..4)?;
+    [(b3dm::MAGIC, "b3dm"), (cmpt::MAGIC, "cmpt"), (i3dm::MAGIC, "i3dm"), (pnts::MAGIC, "pnts"), (glb::MAGIC, "glb")]
+        .into_iter()
+        .find(|(m, _)| magic == &m[..])
+        .map(|(_, extension)| extension)
+}
+
+// e.g. tiles/123model, which the tileserver serves without an extension
+fn is_extensionless_model(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.strip_suffix("model").is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// The model a GLB was converted from, preferring the whole name when a model
+// has it, e.g. 123model_2.glb is 123model_2 rather than inner tile 2 of 123model
+fn glb_model<'a, V>(stem: &'a str, models: &HashMap<String, V>) -> Option<(&'a str, Option<usize>, Options)> {
+    if models.contains_key(stem) {
+        return Some((stem, None, Options::default()));
+    }
+    let mut options = Options::default();
+    let mut rest = stem;
+    if let Some(baked) = rest.strip_suffix("_baked") {
+        options.instancing = Instancing::Baked;
+        rest = baked;
+        if models.contains_key(rest) {
+            return Some((rest, None, options));
+        }
+    }
+    let (model, index) = rest.rsplit_once('_')?;
+    let index = index.parse().ok()?;
+    models.contains_key(model).then_some((model, Some(index), options))
+}
+
+// Files below a dir with their path relative to it, leaving temp files to the cleanup of orphans
+fn list_files(dir: &str) -> Vec<(PathBuf, String)> {
+    fn walk(dir: &Path, prefix: &str, files: &mut Vec<(PathBuf, String)>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let relative = format!("{}{}", prefix, name);
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => walk(&entry.path(), &format!("{}/", relative), files),
+                Ok(_) if !cache::is_temp_file(&name) => files.push((entry.path(), relative)),
+                _ => {}
+            }
+        }
+    }
+    let mut files = Vec::new();
+    walk(Path::new(dir), "", &mut files);
+    files
+}
+
+fn remove_empty_dirs(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_dir()) && entry.file_name() != "blobs" {
+            remove_empty_dirs(&entry.path());
+        }
+    }
+    // Fails unless it's empty
+    let _ = fs::remove_dir(dir);
+}
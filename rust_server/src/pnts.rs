@@ -0,0 +1,204 @@
+use serde_json::{json, Value};
+
+use crate::{
+    glb::{read_u32, Glb},
+    table::Table,
+};
+
+pub const MAGIC: &[u8; 4] = b"pnts";
+const HEADER_LENGTH: usize = 28;
+
+/// A Point Cloud tile.
+pub struct Pnts {
+    pub version: u32,
+    pub feature_table: Table,
+    pub batch_table: Table,
+}
+
+impl Pnts {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Pnts, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..4] != MAGIC {
+            return Err("Missing pnts magic in tile header".to_string());
+        }
+        let version = read_u32(bytes, 4)?;
+        let byte_length = (read_u32(bytes, 8)? as usize).min(bytes.len());
+
+        let mut offset = HEADER_LENGTH;
+        let mut sections = Vec::with_capacity(4);
+        for field in [12, 16, 20, 24] {
+            let end = offset + read_u32(bytes, field)? as usize;
+            if end > byte_length {
+                return Err("pnts tables exceed the tile length".to_string());
+            }
+            sections.push(&bytes[offset..end]);
+            offset = end;
+        }
+
+        Ok(Pnts {
+            version,
+            feature_table: Table::from_slices(sections[0], sections[1])?,
+            batch_table: Table::from_slices(sections[2], sections[3])?,
+        })
+    }
+
+    pub fn points_length(&self) -> usize {
+        self.feature_table.global_u32("POINTS_LENGTH").unwrap_or(0) as usize
+    }
+
+    /// Convert the point cloud to a GLB with a single `POINTS` primitive.
+    ///
+    /// Positions and normals are rotated from the tile's Z-up frame to glTF's
+    /// Y-up frame, and colors are converted from sRGB to linear.
+    pub fn to_glb(&self) -> Result<Vec<u8>, String> {
+        let table = &self.feature_table;
+        let count = self.points_length();
+        // glTF accessors can't be empty, and their bounds need at least one point
+        if count == 0 {
+            return Err("pnts has no points".to_string());
+        }
+        if table.json.pointer("/extensions/3DTILES_draco_point_compression").is_some() {
+            return Err("Draco compressed point clouds aren't supported".to_string());
+        }
+
+        let positions: Vec<f32> = if let Some(positions) = table.property_f32("POSITION", count, 3)? {
+            positions
+        } else if let Some(quantized) = table.property_u16("POSITION_QUANTIZED", count, 3)? {
+            let (Some(offset), Some(scale)) = (table.global_vec3("QUANTIZED_VOLUME_OFFSET"), table.global_vec3("QUANTIZED_VOLUME_SCALE")) else {
+                return Err("pnts has POSITION_QUANTIZED without a quantized volume".to_string());
+            };
+            quantized.iter().enumerate().map(|(i, q)| (offset[i % 3] + *q as f64 / 65535.0 * scale[i % 3]) as f32).collect()
+        } else {
+            return Err("pnts has neither POSITION nor POSITION_QUANTIZED".to_string());
+        };
+        let positions = z_up_to_y_up(&positions);
+
+        let mut glb = Glb { json: json!({ "asset": { "version": "2.0", "generator": "tileset_conversion_server" } }), bin: Vec::new() };
+        let mut attributes = serde_json::Map::new();
+
+        let position = glb.push_float_accessor(&positions, "VEC3")?;
+        let (min, max) = bounds(&positions);
+        glb.json["accessors"][position]["min"] = json!(min);
+        glb.json["accessors"][position]["max"] = json!(max);
+        attributes.insert("POSITION".to_string(), json!(position));
+
+        let colors = self.colors(count)?;
+        let has_colors = colors.is_some();
+        if let Some(colors) = colors {
+            let accessor_type = if colors.len() == count * 4 { "VEC4" } else { "VEC3" };
+            attributes.insert("COLOR_0".to_string(), json!(glb.push_float_accessor(&colors, accessor_type)?));
+        }
+
+        let normals = if let Some(normals) = table.property_f32("NORMAL", count, 3)? {
+            Some(normals)
+        } else {
+            table.property_u8("NORMAL_OCT16P", count, 2)?.map(|encoded| oct_decode_all(&encoded))
+        };
+        if let Some(normals) = normals {
+            attributes.insert("NORMAL".to_string(), json!(glb.push_float_accessor(&z_up_to_y_up(&normals), "VEC3")?));
+        }
+
+        let mut primitive = json!({ "mode": 0, "material": 0 });
+        if let Some(batch_ids) = self.batch_ids(count)? {
+            let feature_count = table.global_u32("BATCH_LENGTH").unwrap_or_else(|| batch_ids.iter().fold(0.0f32, |m, id| m.max(*id)) as u32 + 1);
+            attributes.insert("_FEATURE_ID_0".to_string(), json!(glb.push_float_accessor(&batch_ids, "SCALAR")?));
+            primitive["extensions"] = json!({ "EXT_mesh_features": { "featureIds": [{ "featureCount": feature_count, "attribute": 0 }] } });
+            glb.add_extension_used("EXT_mesh_features");
+        }
+        primitive["attributes"] = Value::Object(attributes);
+
+        // CONSTANT_RGBA is only the color of points without colors of their own
+        let base_color = match table.json.get("CONSTANT_RGBA").and_then(Value::as_array) {
+            Some(rgba) if rgba.len() == 4 && !has_colors => {
+                let c: Vec<f32> = rgba.iter().map(|v| v.as_f64().unwrap_or(255.0) as f32 / 255.0).collect();
+                [srgb_to_linear(c[0]), srgb_to_linear(c[1]), srgb_to_linear(c[2]), c[3]]
+            }
+            _ => [1.0; 4],
+        };
+        glb.json["materials"] = json!([{ "pbrMetallicRoughness": { "baseColorFactor": base_color, "metallicFactor": 0.0 }, "extensions": { "KHR_materials_unlit": {} } }]);
+        glb.add_extension_used("KHR_materials_unlit");
+        glb.json["meshes"] = json!([{ "primitives": [primitive] }]);
+        glb.json["nodes"] = json!([{ "mesh": 0 }]);
+        glb.json["scenes"] = json!([{ "nodes": [0] }]);
+        glb.json["scene"] = json!(0);
+
+        if let Some(center) = table.global_vec3("RTC_CENTER") {
+            glb.add_extension("CESIUM_RTC", json!({ "center": center }));
+        }
+        Ok(glb.to_bytes())
+    }
+
+    // Linear colors as RGBA or RGB floats, from whichever color property the tile has.
+    fn colors(&self, count: usize) -> Result<Option<Vec<f32>>, String> {
+        let table = &self.feature_table;
+        if let Some(rgba) = table.property_u8("RGBA", count, 4)? {
+            return Ok(Some(rgba.chunks_exact(4).flat_map(|c| {
+                [srgb_to_linear(c[0] as f32 / 255.0), srgb_to_linear(c[1] as f32 / 255.0), srgb_to_linear(c[2] as f32 / 255.0), c[3] as f32 / 255.0]
+            }).collect()));
+        }
+        if let Some(rgb) = table.property_u8("RGB", count, 3)? {
+            return Ok(Some(rgb.iter().map(|c| srgb_to_linear(*c as f32 / 255.0)).collect()));
+        }
+        if let Some(rgb565) = table.property_u16("RGB565", count, 1)? {
+            return Ok(Some(rgb565.iter().flat_map(|c| {
+                let r = ((c >> 11) & 0x1F) as f32 / 31.0;
+                let g = ((c >> 5) & 0x3F) as f32 / 63.0;
+                let b = (c & 0x1F) as f32 / 31.0;
+                [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b)]
+            }).collect()));
+        }
+        Ok(None)
+    }
+
+    fn batch_ids(&self, count: usize) -> Result<Option<Vec<f32>>, String> {
+        let table = &self.feature_table;
+        let component_type = table.json.pointer("/BATCH_ID/componentType").and_then(Value::as_str).unwrap_or("UNSIGNED_SHORT");
+        let ids = match component_type {
+            "UNSIGNED_BYTE" => table.property_u8("BATCH_ID", count, 1)?.map(|ids| ids.iter().map(|id| *id as f32).collect()),
+            "UNSIGNED_SHORT" => table.property_u16("BATCH_ID", count, 1)?.map(|ids| ids.iter().map(|id| *id as f32).collect()),
+            "UNSIGNED_INT" => table.property_u32("BATCH_ID", count, 1)?.map(|ids| ids.iter().map(|id| *id as f32).collect()),
+            other => return Err(format!("Unsupported BATCH_ID component type {}", other)),
+        };
+        Ok(ids)
+    }
+}
+
+/// Convert the bytes of a pnts tile to a GLB.
+pub fn pnts_to_glb(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    Pnts::from_bytes(bytes)?.to_glb()
+}
+
+fn z_up_to_y_up(vectors: &[f32]) -> Vec<f32> {
+    vectors.chunks_exact(3).flat_map(|v| [v[0], v[2], -v[1]]).collect()
+}
+
+fn bounds(positions: &[f32]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for p in positions.chunks_exact(3) {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(p[axis]);
+            max[axis] = max[axis].max(p[axis]);
+        }
+    }
+    (min, max)
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+// Decode oct-encoded unit vectors with 8 bits per component.
+fn oct_decode_all(encoded: &[u8]) -> Vec<f32> {
+    encoded.chunks_exact(2).flat_map(|e| {
+        let mut x = e[0] as f32 / 255.0 * 2.0 - 1.0;
+        let mut y = e[1] as f32 / 255.0 * 2.0 - 1.0;
+        let z = 1.0 - x.abs() - y.abs();
+        if z < 0.0 {
+            let (old_x, old_y) = (x, y);
+            x = (1.0 - old_y.abs()) * old_x.signum();
+            y = (1.0 - old_x.abs()) * old_y.signum();
+        }
+        let length = (x * x + y * y + z * z).sqrt();
+        [x / length, y / length, z / length]
+    }).collect()
+}
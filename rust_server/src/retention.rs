@@ -0,0 +1,112 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Deserialize;
+
+/// Which copies of a model are kept on disk once it's converted.
+///
+/// Whichever copy isn't kept is made again when it's needed: a missing source
+/// model is fetched again, and a GLB that isn't on disk is converted again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum Retention {
+    /// The fetched source model and the converted GLB.
+    #[default]
+    #[serde(rename = "both")]
+    Both,
+    /// Only the GLB, the source model is removed after converting it.
+    #[serde(rename = "glb")]
+    Glb,
+    /// Only the source model, GLBs are converted on demand and kept in memory.
+    #[serde(rename = "source")]
+    Source,
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Retention, String> {
+        match s {
+            "both" => Ok(Retention::Both),
+            "glb" => Ok(Retention::Glb),
+            "source" => Ok(Retention::Source),
+            _ => Err(format!("Unknown source retention {:?}, expected both, glb or source", s)),
+        }
+    }
+}
+
+/// A GLB converted in memory.
+#[derive(Clone, Debug)]
+pub struct MemoryGlb {
+    pub bytes: Arc<Vec<u8>>,
+    pub etag: String,
+    /// The blob of the source model it was converted from, which tells whether it's still current.
+    pub source_blob: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// Keeps the GLBs converted in memory below a byte limit, evicting the least recently used ones.
+pub struct MemoryCache {
+    limit: u64,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    entries: HashMap<String, (MemoryGlb, u64)>,
+    order: BTreeMap<u64, String>,
+    total: u64,
+    clock: u64,
+}
+
+impl MemoryCache {
+    pub fn new(limit: u64) -> MemoryCache {
+        MemoryCache { limit, state: Mutex::new(MemoryState::default()) }
+    }
+
+    pub fn get(&self, key: &str) -> Option<MemoryGlb> {
+        let mut state = self.state.lock().unwrap();
+        let (glb, tick) = state.entries.get(key).cloned()?;
+        state.order.remove(&tick);
+        state.clock += 1;
+        let clock = state.clock;
+        state.order.insert(clock, key.to_string());
+        if let Some(entry) = state.entries.get_mut(key) {
+            entry.1 = clock;
+        }
+        Some(glb)
+    }
+
+    /// Keep a GLB, unless it's larger than the whole cache.
+    pub fn insert(&self, key: &str, glb: MemoryGlb) {
+        let size = glb.bytes.len() as u64;
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+        if size > self.limit {
+            return;
+        }
+        while state.total + size > self.limit {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+        state.clock += 1;
+        let clock = state.clock;
+        state.total += size;
+        state.order.insert(clock, key.to_string());
+        state.entries.insert(key.to_string(), (glb, clock));
+    }
+}
+
+impl MemoryState {
+    fn remove(&mut self, key: &str) {
+        if let Some((glb, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.total -= glb.bytes.len() as u64;
+        }
+    }
+}
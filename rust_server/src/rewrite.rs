@@ -0,0 +1,63 @@
+use crate::{
+    http::Request,
+    tileset::{self, Tileset},
+    upgrade::{self, glb_uri},
+};
+
+/// Query parameters meant for this server, e.g. `tile=2`, which aren't forwarded upstream.
+pub const OWN_QUERY_PARAMS: [&str; 2] = ["tile", "instancing"];
+
+/// Point every content URI in a tileset at this server.
+///
+/// `tileset_path` is where the tileset lives relative to `upstream_url`, and
+/// content is resolved against it. Models get their converted `.glb` URI.
+/// With a `public_url` the URIs become absolute URLs below it, otherwise
+/// they are relative to the tileset, so they work wherever the server is
+/// reachable. Content hosted outside of `upstream_url` is left untouched.
+pub fn rewrite_content_uris(tileset: &mut Tileset, tileset_path: &str, upstream_url: &str, public_url: Option<&str>) {
+    let without_query = tileset_path.split(['?', '#']).next().unwrap_or(tileset_path);
+    let tileset_dir = without_query.rfind('/').map_or("", |i| &without_query[..=i]);
+    tileset.for_each_tile_mut(|tile| {
+        for content in tile.contents_mut() {
+            let Some(uri) = content.uri.as_ref().or(content.url.as_ref()) else {
+                continue;
+            };
+            let Some(path) = tileset::resolve_content_uri(upstream_url, tileset_path, uri) else {
+                continue;
+            };
+            let path = glb_uri(&path);
+            let rewritten = match public_url {
+                Some(base) if base.ends_with('/') => format!("{}{}", base, path),
+                Some(base) => format!("{}/{}", base, path),
+                None => relative_path(tileset_dir, &path),
+            };
+            if content.url.is_some() && content.uri.is_none() {
+                content.url = Some(rewritten);
+            } else {
+                content.uri = Some(rewritten);
+            }
+        }
+    });
+}
+
+/// The upstream path of content requested at `path` relative to its source, the reverse of [`rewrite_content_uris`].
+///
+/// Converted models map back to their source model, and the query of the
+/// request is kept apart from this server's own parameters, so it's the same
+/// URL the fetcher finds in the tileset, e.g. a versioned or signed one.
+pub fn upstream_path(path: &str, request: &Request) -> String {
+    let path = upgrade::source_uri(path).unwrap_or_else(|| path.to_string());
+    path + &request.query_string(&OWN_QUERY_PARAMS)
+}
+
+// Express `path` relative to the directory `from_dir`, both relative to the same root.
+fn relative_path(from_dir: &str, path: &str) -> String {
+    let from: Vec<&str> = from_dir.split('/').filter(|s| !s.is_empty()).collect();
+    let split = path.find(['?', '#']).unwrap_or(path.len());
+    let to: Vec<&str> = path[..split].split('/').collect();
+
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count().min(to.len() - 1);
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(&to[common..]);
+    parts.join("/") + &path[split..]
+}
@@ -0,0 +1,90 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Deduplicates concurrent work on the same key.
+///
+/// The first caller for a key runs the work, and callers arriving while it
+/// runs wait for it and get a clone of its result instead of doing the
+/// same work again. Keys are cache paths, so the work is e.g. fetching or
+/// converting the file at that path.
+pub struct SingleFlight<T> {
+    calls: Mutex<HashMap<String, Arc<Call<T>>>>,
+}
+
+struct Call<T> {
+    state: Mutex<CallState<T>>,
+    done: Condvar,
+}
+
+enum CallState<T> {
+    Running,
+    Finished(T),
+    /// The work panicked, so waiters have to do it themselves.
+    Abandoned,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> SingleFlight<T> {
+        SingleFlight { calls: Mutex::new(HashMap::new()) }
+    }
+
+    /// Run `work` for `key`, unless it's already running, in which case wait for that result.
+    pub fn run<F: FnOnce() -> T>(&self, key: &str, work: F) -> T {
+        let (call, leader) = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(key) {
+                Some(call) => (Arc::clone(call), false),
+                None => {
+                    let call = Arc::new(Call { state: Mutex::new(CallState::Running), done: Condvar::new() });
+                    calls.insert(key.to_string(), Arc::clone(&call));
+                    (call, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut state = call.state.lock().unwrap();
+            while matches!(*state, CallState::Running) {
+                state = call.done.wait(state).unwrap();
+            }
+            if let CallState::Finished(result) = &*state {
+                return result.clone();
+            }
+            drop(state);
+            return work();
+        }
+
+        // Wakes the waiters even if the work panics
+        let finish = Finish { flight: self, key, call: &call };
+        let result = work();
+        *call.state.lock().unwrap() = CallState::Finished(result.clone());
+        drop(finish);
+        result
+    }
+}
+
+impl<T: Clone> Default for SingleFlight<T> {
+    fn default() -> SingleFlight<T> {
+        SingleFlight::new()
+    }
+}
+
+struct Finish<'a, T> {
+    flight: &'a SingleFlight<T>,
+    key: &'a str,
+    call: &'a Call<T>,
+}
+
+impl<T> Drop for Finish<'_, T> {
+    fn drop(&mut self) {
+        // Later callers start over, by then the result is in the cache
+        self.flight.calls.lock().unwrap_or_else(|e| e.into_inner()).remove(self.key);
+        let mut state = self.call.state.lock().unwrap_or_else(|e| e.into_inner());
+        if matches!(*state, CallState::Running) {
+            *state = CallState::Abandoned;
+        }
+        self.call.done.notify_all();
+    }
+}
@@ -0,0 +1,106 @@
+use reqwest::blocking::{Client, RequestBuilder};
+use serde::Deserialize;
+
+use crate::store::Key;
+
+/// How requests to an upstream are authenticated.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "scheme", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Auth {
+    #[default]
+    None,
+    /// A query parameter, e.g. `{ scheme = "query", name = "api_key", value = "..." }`.
+    Query { name: String, value: String },
+    /// A request header, e.g. `{ scheme = "header", name = "X-Api-Key", value = "..." }`.
+    Header { name: String, value: String },
+    /// `Authorization: Bearer <token>`.
+    Bearer { token: String },
+    /// HTTP basic authentication.
+    Basic { username: String, password: Option<String> },
+}
+
+/// An upstream as written in the config file, e.g.
+///
+/// ```toml
+/// [sources.buildings]
+/// url = "https://tiles.example.com/buildings/"
+/// auth = { scheme = "bearer", token = "..." }
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub url: String,
+    #[serde(default)]
+    pub auth: Auth,
+    /// Keeps this source's cached content apart from other sources, defaults to its name.
+    pub cache_namespace: Option<String>,
+}
+
+/// An upstream tileserver whose content is served by this server.
+///
+/// Named sources are served below `/<name>/`, while the source made from the
+/// top-level `tileserver_url` is served at the root of the server.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub name: Option<String>,
+    /// Base URL of the upstream, always ending in a slash.
+    pub url: String,
+    pub auth: Auth,
+    /// Keeps the cached content of this source apart, empty for the root source.
+    pub namespace: String,
+    /// Root of this source's tileset, b3dm and glb directories of the flat cache layout.
+    pub cache_dir: String,
+}
+
+impl Source {
+    pub fn new(name: Option<&str>, url: &str, auth: Auth, namespace: &str, cache_dir: String) -> Source {
+        // Content URIs are resolved against the base URL, which drops a last segment without a slash
+        let url = if url.ends_with('/') { url.to_string() } else { format!("{}/", url) };
+        Source { name: name.map(str::to_string), url, auth, namespace: namespace.to_string(), cache_dir }
+    }
+
+    /// The path this source is served under, e.g. `buildings/`, or an empty string at the root.
+    pub fn route_prefix(&self) -> String {
+        self.name.as_ref().map_or_else(String::new, |name| format!("{}/", name))
+    }
+
+    /// The key a path relative to the source is cached under, e.g. with the variant `glb` for its converted GLB.
+    ///
+    /// The query isn't part of it, e.g. a version or signature of the same content.
+    pub fn key(&self, path: &str, variant: &str) -> Key {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        Key { namespace: self.namespace.clone(), url: self.url_of(path), variant: variant.to_string() }
+    }
+
+    // The flat cache layout, which is migrated into the store at startup
+    pub fn tileset_dir(&self) -> String {
+        format!("{}/tilesets", self.cache_dir)
+    }
+
+    pub fn b3dm_dir(&self) -> String {
+        format!("{}/b3dms", self.cache_dir)
+    }
+
+    pub fn glb_dir(&self) -> String {
+        format!("{}/glbs", self.cache_dir)
+    }
+
+    /// The upstream URL of a path relative to the source, which may already have a query.
+    ///
+    /// Credentials aren't part of it, so it's fine to log.
+    pub fn url_of(&self, path: &str) -> String {
+        self.url.clone() + path
+    }
+
+    /// A GET request for a path relative to the source, with its credentials.
+    pub fn get(&self, client: &Client, path: &str) -> RequestBuilder {
+        let request = client.get(self.url_of(path));
+        match &self.auth {
+            Auth::None => request,
+            Auth::Query { name, value } => request.query(&[(name, value)]),
+            Auth::Header { name, value } => request.header(name.as_str(), value.as_str()),
+            Auth::Bearer { token } => request.bearer_auth(token),
+            Auth::Basic { username, password } => request.basic_auth(username, password.as_ref()),
+        }
+    }
+}
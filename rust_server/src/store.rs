@@ -0,0 +1,489 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+
+use crate::{cache, upstream::Validators};
+
+/// The index next to the blobs in the cache dir.
+pub const INDEX_FILE: &str = "index.sqlite3";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    namespace TEXT NOT NULL,
+    url TEXT NOT NULL,
+    variant TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    blob TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    fetched_at INTEGER NOT NULL,
+    accessed_at INTEGER NOT NULL,
+    etag TEXT,
+    last_modified TEXT,
+    params TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (namespace, url, variant)
+);
+CREATE INDEX IF NOT EXISTS entries_by_use ON entries (kind, accessed_at);
+CREATE INDEX IF NOT EXISTS entries_by_blob ON entries (blob);
+CREATE TABLE IF NOT EXISTS missing (
+    namespace TEXT NOT NULL,
+    url TEXT NOT NULL,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    requests INTEGER NOT NULL,
+    first_requested_at INTEGER NOT NULL,
+    last_requested_at INTEGER NOT NULL,
+    PRIMARY KEY (namespace, url)
+);
+CREATE TABLE IF NOT EXISTS kind_sizes (
+    kind TEXT PRIMARY KEY,
+    size INTEGER NOT NULL
+);
+CREATE TRIGGER IF NOT EXISTS entries_inserted AFTER INSERT ON entries BEGIN
+    INSERT INTO kind_sizes (kind, size) VALUES (NEW.kind, NEW.size) ON CONFLICT (kind) DO UPDATE SET size = size + NEW.size;
+END;
+CREATE TRIGGER IF NOT EXISTS entries_deleted AFTER DELETE ON entries BEGIN
+    UPDATE kind_sizes SET size = size - OLD.size WHERE kind = OLD.kind;
+END;
+CREATE TRIGGER IF NOT EXISTS entries_updated AFTER UPDATE OF kind, size ON entries BEGIN
+    UPDATE kind_sizes SET size = size - OLD.size WHERE kind = OLD.kind;
+    INSERT INTO kind_sizes (kind, size) VALUES (NEW.kind, NEW.size) ON CONFLICT (kind) DO UPDATE SET size = size + NEW.size;
+END;
+";
+
+// Sizes of indexes written before the running totals were kept, which the triggers keep up to date from then on
+const FILL_KIND_SIZES: &str = "INSERT OR IGNORE INTO kind_sizes (kind, size) SELECT kind, SUM(size) FROM entries GROUP BY kind";
+
+const COLUMNS: &str = "kind, blob, content_type, size, fetched_at, etag, last_modified, params";
+
+/// The kinds of cached content, which have separate quotas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Tilesets,
+    Models,
+    Glbs,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Tilesets => "tileset",
+            Kind::Models => "model",
+            Kind::Glbs => "glb",
+        }
+    }
+
+    fn from_name(name: &str) -> Kind {
+        match name {
+            "tileset" => Kind::Tilesets,
+            "model" => Kind::Models,
+            _ => Kind::Glbs,
+        }
+    }
+}
+
+/// What an entry is cached under.
+///
+/// The URL is the upstream URL of the content, and the variant tells apart
+/// what's derived from it, e.g. `glb` or `glb;tile=2;baked` for converted
+/// models and `br` for a compressed tileset. The namespace keeps sources
+/// apart that share an upstream but e.g. authenticate differently.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub namespace: String,
+    pub url: String,
+    pub variant: String,
+}
+
+impl Key {
+    /// The same key with another variant.
+    pub fn variant(&self, variant: &str) -> Key {
+        Key { variant: variant.to_string(), ..self.clone() }
+    }
+
+    /// A single string for the key, e.g. to deduplicate work on it.
+    pub fn id(&self) -> String {
+        format!("{}|{}|{}", self.namespace, self.url, self.variant)
+    }
+}
+
+/// Content that was requested while offline but wasn't cached.
+#[derive(Clone, Debug)]
+pub struct Missing {
+    pub namespace: String,
+    pub url: String,
+    /// The path relative to the source, e.g. `tiles/123model.b3dm`.
+    pub path: String,
+    pub kind: Kind,
+    pub requests: u64,
+    /// Seconds since the Unix epoch.
+    pub first_requested_at: u64,
+    pub last_requested_at: u64,
+}
+
+/// An entry of the index, and where its contents are.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub kind: Kind,
+    /// The SHA-256 of the contents in hex, which is also the name of the blob.
+    pub blob: String,
+    pub path: PathBuf,
+    pub content_type: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch when upstream last sent or confirmed the contents.
+    pub fetched_at: u64,
+    pub validators: Validators,
+    /// How derived content was made, e.g. the converter and source blob of a GLB.
+    pub params: String,
+}
+
+/// Cached content in blobs named after the hash of their contents, with an index of what they are.
+///
+/// Blobs are sharded by the first bytes of their hash, e.g.
+/// blobs/3f/a2/3fa2..., so no directory grows too large, and the same
+/// content is only stored once. The index is a SQLite database, which the
+/// server and the fetcher may use at the same time.
+pub struct Store {
+    blob_dir: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+impl Store {
+    /// Open the store in `cache_dir`, creating it if needed.
+    pub fn open(cache_dir: &str) -> Result<Store, String> {
+        let blob_dir = Path::new(cache_dir).join("blobs");
+        fs::create_dir_all(&blob_dir).map_err(|e| format!("Unable to create {}: {}", blob_dir.display(), e))?;
+        let index = Path::new(cache_dir).join(INDEX_FILE);
+        let open = || -> rusqlite::Result<Connection> {
+            let connection = Connection::open(&index)?;
+            // Waits for the other binary instead of failing while it writes
+            connection.busy_timeout(Duration::from_secs(10))?;
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+            // In one transaction, so the totals are filled before the other binary writes
+            connection.execute_batch(&format!("BEGIN IMMEDIATE; {} {}; COMMIT;", SCHEMA, FILL_KIND_SIZES))?;
+            Ok(connection)
+        };
+        let connection = open().map_err(|e| format!("Unable to open cache index {}: {}", index.display(), e))?;
+        Ok(Store { blob_dir, connection: Mutex::new(connection) })
+    }
+
+    /// Where the blob with a hash is stored.
+    pub fn blob_path(&self, blob: &str) -> PathBuf {
+        self.blob_dir.join(&blob[..2]).join(&blob[2..4]).join(blob)
+    }
+
+    /// A temp file for content that's added with [`Store::put_file`] once it's complete.
+    pub fn temp_path(&self) -> PathBuf {
+        cache::temp_path(&self.blob_dir.join("blob"))
+    }
+
+    /// Look up an entry, and count it as used.
+    ///
+    /// Entries whose blob went missing are removed.
+    pub fn get(&self, key: &Key) -> Option<Entry> {
+        let connection = self.connection.lock().unwrap();
+        let entry = connection.query_row(
+            &format!("SELECT {} FROM entries WHERE namespace = ?1 AND url = ?2 AND variant = ?3", COLUMNS),
+            params![key.namespace, key.url, key.variant],
+            |row| self.entry_of(row),
+        ).optional().unwrap_or_else(|e| { println!("Unable to read cache index: {}", e); None })?;
+
+        if !entry.path.exists() {
+            let _ = connection.execute("DELETE FROM entries WHERE namespace = ?1 AND url = ?2 AND variant = ?3", params![key.namespace, key.url, key.variant]);
+            return None;
+        }
+        let _ = connection.execute(
+            "UPDATE entries SET accessed_at = ?4 WHERE namespace = ?1 AND url = ?2 AND variant = ?3",
+            params![key.namespace, key.url, key.variant, unix_millis()],
+        );
+        Some(entry)
+    }
+
+    /// Store content under a key, replacing what was there.
+    pub fn put(&self, key: &Key, kind: Kind, content_type: &str, contents: &[u8], validators: &Validators, params: &str) -> Result<Entry, String> {
+        let blob = format!("{:x}", Sha256::digest(contents));
+        let path = self.blob_path(&blob);
+        if !path.exists() {
+            cache::write_atomic(&path, contents).map_err(|e| format!("Error when writing blob {}: {}", path.display(), e))?;
+        }
+        self.insert(key, kind, content_type, &blob, contents.len() as u64, validators, params)
+    }
+
+    /// Store a complete temp file from [`Store::temp_path`] under a key, replacing what was there.
+    pub fn put_file(&self, key: &Key, kind: Kind, content_type: &str, temp: &Path, validators: &Validators, params: &str) -> Result<Entry, String> {
+        let hashed = hash_file(temp);
+        let (blob, size) = match hashed {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                let _ = fs::remove_file(temp);
+                return Err(format!("Unable to read {}: {}", temp.display(), e));
+            }
+        };
+        let path = self.blob_path(&blob);
+        if path.exists() {
+            let _ = fs::remove_file(temp);
+        } else {
+            let moved = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| cache::persist(temp, &path));
+            moved.map_err(|e| format!("Error when writing blob {}: {}", path.display(), e))?;
+        }
+        self.insert(key, kind, content_type, &blob, size, validators, params)
+    }
+
+    /// Record that upstream confirmed an entry is current, along with its new validators.
+    pub fn set_validated(&self, key: &Key, validators: &Validators) {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE entries SET etag = ?4, last_modified = ?5, fetched_at = ?6 WHERE namespace = ?1 AND url = ?2 AND variant = ?3",
+            params![key.namespace, key.url, key.variant, validators.etag, validators.last_modified, unix_time(SystemTime::now())],
+        );
+        if let Err(e) = updated {
+            println!("Unable to update cache index: {}", e);
+        }
+    }
+
+    /// Remove an entry, and its blob unless other entries have the same content.
+    pub fn remove(&self, key: &Key) {
+        let connection = self.connection.lock().unwrap();
+        let removed = connection.query_row(
+            "DELETE FROM entries WHERE namespace = ?1 AND url = ?2 AND variant = ?3 RETURNING blob",
+            params![key.namespace, key.url, key.variant],
+            |row| row.get::<_, String>(0),
+        ).optional();
+        if let Ok(Some(blob)) = removed {
+            self.remove_unreferenced_blob(&connection, &blob);
+        }
+    }
+
+    /// Remove every variant of an upstream URL, e.g. a model and the GLBs converted from it.
+    ///
+    /// Returns how many entries were removed.
+    pub fn remove_url(&self, namespace: &str, url: &str) -> usize {
+        let connection = self.connection.lock().unwrap();
+        let removed = connection
+            .prepare("DELETE FROM entries WHERE namespace = ?1 AND url = ?2 RETURNING blob")
+            .and_then(|mut statement| statement.query_map(params![namespace, url], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>())
+            .unwrap_or_default();
+        for blob in &removed {
+            self.remove_unreferenced_blob(&connection, blob);
+        }
+        removed.len()
+    }
+
+    /// The bytes cached of a kind.
+    pub fn size(&self, kind: Kind) -> u64 {
+        let connection = self.connection.lock().unwrap();
+        let size: Option<i64> = connection.query_row("SELECT size FROM kind_sizes WHERE kind = ?1", params![kind.name()], |row| row.get(0)).optional().unwrap_or(None);
+        size.unwrap_or(0).max(0) as u64
+    }
+
+    /// Remove the least recently used entries of a kind until it's at most `quota` bytes.
+    ///
+    /// `keep` isn't removed, even if it exceeds the quota on its own. Returns how many entries were removed.
+    pub fn evict(&self, kind: Kind, quota: u64, keep: Option<&Key>) -> usize {
+        let excess = self.size(kind).saturating_sub(quota);
+        if excess == 0 {
+            return 0;
+        }
+        // Only reads as many of the least recently used entries as it takes to free enough
+        let connection = self.connection.lock().unwrap();
+        let mut victims = Vec::new();
+        let selected = connection.prepare("SELECT namespace, url, variant, size FROM entries WHERE kind = ?1 ORDER BY accessed_at").and_then(|mut statement| {
+            let mut rows = statement.query(params![kind.name()])?;
+            let mut freed = 0;
+            while let Some(row) = rows.next()? {
+                let key = Key { namespace: row.get(0)?, url: row.get(1)?, variant: row.get(2)? };
+                if Some(&key) == keep {
+                    continue;
+                }
+                victims.push(key);
+                freed += row.get::<_, i64>(3)? as u64;
+                if freed >= excess {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = selected {
+            println!("Unable to read cache index: {}", e);
+        }
+        drop(connection);
+        for key in &victims {
+            self.remove(key);
+        }
+        victims.len()
+    }
+
+    /// Remember that content was requested but isn't cached, so it can be prefetched later.
+    ///
+    /// It's forgotten again once it's cached.
+    pub fn record_missing(&self, key: &Key, path: &str, kind: Kind) {
+        let now = unix_time(SystemTime::now()) as i64;
+        let connection = self.connection.lock().unwrap();
+        let recorded = connection.execute(
+            "INSERT INTO missing (namespace, url, path, kind, requests, first_requested_at, last_requested_at) VALUES (?1, ?2, ?3, ?4, 1, ?5, ?5)
+             ON CONFLICT (namespace, url) DO UPDATE SET requests = requests + 1, last_requested_at = ?5",
+            params![key.namespace, key.url, path, kind.name(), now],
+        );
+        if let Err(e) = recorded {
+            println!("Unable to record missing {}: {}", key.url, e);
+        }
+    }
+
+    /// The content requested while it wasn't cached, most requested first.
+    pub fn missing(&self) -> Vec<Missing> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare("SELECT namespace, url, path, kind, requests, first_requested_at, last_requested_at FROM missing ORDER BY requests DESC, first_requested_at")
+            .and_then(|mut statement| {
+                statement.query_map([], |row| Ok(Missing {
+                    namespace: row.get(0)?,
+                    url: row.get(1)?,
+                    path: row.get(2)?,
+                    kind: Kind::from_name(&row.get::<_, String>(3)?),
+                    requests: row.get::<_, i64>(4)? as u64,
+                    first_requested_at: row.get::<_, i64>(5)? as u64,
+                    last_requested_at: row.get::<_, i64>(6)? as u64,
+                }))?.collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Make the index and the blobs agree after e.g. a crash or blobs removed by hand.
+    ///
+    /// Entries without a blob are removed, and so are blobs without an entry
+    /// once they're old enough not to belong to a writer that's still adding
+    /// them. Returns how many entries and blobs were removed.
+    pub fn reconcile(&self) -> (usize, usize) {
+        let connection = self.connection.lock().unwrap();
+        let blobs: HashSet<String> = connection
+            .prepare("SELECT DISTINCT blob FROM entries")
+            .and_then(|mut statement| statement.query_map([], |row| row.get(0))?.collect())
+            .unwrap_or_default();
+
+        let mut removed_entries = 0;
+        for blob in blobs.iter().filter(|blob| !self.blob_path(blob).exists()) {
+            removed_entries += connection.execute("DELETE FROM entries WHERE blob = ?1", params![blob]).unwrap_or(0);
+        }
+
+        let mut files = Vec::new();
+        list_files(&self.blob_dir, &mut files);
+        let mut removed_blobs = 0;
+        for (path, modified) in files {
+            let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            let old_enough = SystemTime::now().duration_since(modified).is_ok_and(|age| age >= cache::ORPHAN_AGE);
+            if !cache::is_temp_file(&name) && !blobs.contains(&name) && old_enough && fs::remove_file(&path).is_ok() {
+                removed_blobs += 1;
+            }
+        }
+        (removed_entries, removed_blobs)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn insert(&self, key: &Key, kind: Kind, content_type: &str, blob: &str, size: u64, validators: &Validators, params: &str) -> Result<Entry, String> {
+        let fetched_at = unix_time(SystemTime::now());
+        let connection = self.connection.lock().unwrap();
+        let replaced: Option<String> = connection
+            .query_row("SELECT blob FROM entries WHERE namespace = ?1 AND url = ?2 AND variant = ?3", params![key.namespace, key.url, key.variant], |row| row.get(0))
+            .optional()
+            .unwrap_or(None);
+        connection.execute(
+            // An upsert rather than a replace, so the triggers keeping the sizes see it as an update
+            "INSERT INTO entries (namespace, url, variant, kind, blob, content_type, size, fetched_at, accessed_at, etag, last_modified, params)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+             ON CONFLICT (namespace, url, variant) DO UPDATE SET kind = excluded.kind, blob = excluded.blob, content_type = excluded.content_type,
+             size = excluded.size, fetched_at = excluded.fetched_at, accessed_at = excluded.accessed_at, etag = excluded.etag,
+             last_modified = excluded.last_modified, params = excluded.params",
+            params![key.namespace, key.url, key.variant, kind.name(), blob, content_type, size as i64, fetched_at as i64, unix_millis(), validators.etag, validators.last_modified, params],
+        ).map_err(|e| format!("Unable to update cache index: {}", e))?;
+        let _ = connection.execute("DELETE FROM missing WHERE namespace = ?1 AND url = ?2", params![key.namespace, key.url]);
+        if let Some(replaced) = replaced.filter(|replaced| replaced != blob) {
+            self.remove_unreferenced_blob(&connection, &replaced);
+        }
+        Ok(Entry {
+            kind,
+            blob: blob.to_string(),
+            path: self.blob_path(blob),
+            content_type: content_type.to_string(),
+            size,
+            fetched_at,
+            validators: validators.clone(),
+            params: params.to_string(),
+        })
+    }
+
+    fn remove_unreferenced_blob(&self, connection: &Connection, blob: &str) {
+        let referenced: bool = connection.query_row("SELECT EXISTS (SELECT 1 FROM entries WHERE blob = ?1)", params![blob], |row| row.get(0)).unwrap_or(true);
+        if !referenced {
+            let _ = fs::remove_file(self.blob_path(blob));
+        }
+    }
+
+    fn entry_of(&self, row: &Row) -> rusqlite::Result<Entry> {
+        let blob: String = row.get(1)?;
+        Ok(Entry {
+            kind: Kind::from_name(&row.get::<_, String>(0)?),
+            path: self.blob_path(&blob),
+            blob,
+            content_type: row.get(2)?,
+            size: row.get::<_, i64>(3)? as u64,
+            fetched_at: row.get::<_, i64>(4)? as u64,
+            validators: Validators { etag: row.get(5)?, last_modified: row.get(6)? },
+            params: row.get(7)?,
+        })
+    }
+}
+
+impl Entry {
+    /// A strong ETag, which is the same as [`conditional::etag_for_bytes`](crate::conditional::etag_for_bytes) of the contents.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", &self.blob[..32])
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&chunk[..read]);
+        size += read as u64;
+    }
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+fn list_files(dir: &Path, files: &mut Vec<(PathBuf, SystemTime)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            list_files(&entry.path(), files);
+        } else {
+            files.push((entry.path(), metadata.modified().unwrap_or(UNIX_EPOCH)));
+        }
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+}
@@ -0,0 +1,87 @@
+use serde_json::Value;
+
+use crate::glb::parse_json;
+
+/// A feature table or batch table: a JSON header followed by a binary body.
+pub struct Table {
+    pub json: Value,
+    pub binary: Vec<u8>,
+}
+
+impl Table {
+    pub fn new(json: Value) -> Table {
+        Table { json, binary: Vec::new() }
+    }
+
+    /// Parse a table from its JSON and binary sections as laid out in a tile.
+    pub fn from_slices(json: &[u8], binary: &[u8]) -> Result<Table, String> {
+        Ok(Table { json: parse_json(json)?, binary: binary.to_vec() })
+    }
+
+    /// Read a scalar global property such as `BATCH_LENGTH`.
+    pub fn global_u32(&self, name: &str) -> Option<u32> {
+        self.json.get(name)?.as_u64().map(|v| v as u32)
+    }
+
+    /// Read a `vec3` global property such as `RTC_CENTER`.
+    ///
+    /// The value may be inline JSON or a `byteOffset` into the binary body.
+    pub fn global_vec3(&self, name: &str) -> Option<[f64; 3]> {
+        let value = self.json.get(name)?;
+        if let Some(values) = value.as_array() {
+            if values.len() != 3 {
+                return None;
+            }
+            return Some([values[0].as_f64()?, values[1].as_f64()?, values[2].as_f64()?]);
+        }
+        let offset = value.get("byteOffset")?.as_u64()? as usize;
+        let mut v = [0.0; 3];
+        for (i, component) in v.iter_mut().enumerate() {
+            let b = self.binary.get(offset + i * 4..offset + i * 4 + 4)?;
+            *component = f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64;
+        }
+        Some(v)
+    }
+
+    /// Read a per-feature `float32` property from the binary body.
+    pub fn property_f32(&self, name: &str, count: usize, components: usize) -> Result<Option<Vec<f32>>, String> {
+        let Some(bytes) = self.property_bytes(name, count * components * 4)? else {
+            return Ok(None);
+        };
+        Ok(Some(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()))
+    }
+
+    /// Read a per-feature `uint32` property from the binary body.
+    pub fn property_u32(&self, name: &str, count: usize, components: usize) -> Result<Option<Vec<u32>>, String> {
+        let Some(bytes) = self.property_bytes(name, count * components * 4)? else {
+            return Ok(None);
+        };
+        Ok(Some(bytes.chunks_exact(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()))
+    }
+
+    /// Read a per-feature `uint16` property from the binary body.
+    pub fn property_u16(&self, name: &str, count: usize, components: usize) -> Result<Option<Vec<u16>>, String> {
+        let Some(bytes) = self.property_bytes(name, count * components * 2)? else {
+            return Ok(None);
+        };
+        Ok(Some(bytes.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect()))
+    }
+
+    /// Read a per-feature `uint8` property from the binary body.
+    pub fn property_u8(&self, name: &str, count: usize, components: usize) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.property_bytes(name, count * components)?.map(<[u8]>::to_vec))
+    }
+
+    fn property_bytes(&self, name: &str, length: usize) -> Result<Option<&[u8]>, String> {
+        let Some(property) = self.json.get(name) else {
+            return Ok(None);
+        };
+        let Some(offset) = property.get("byteOffset").and_then(|o| o.as_u64()) else {
+            return Err(format!("Feature table property {} has no byteOffset", name));
+        };
+        match self.binary.get(offset as usize..offset as usize + length) {
+            Some(bytes) => Ok(Some(bytes)),
+            None => Err(format!("Feature table property {} exceeds the binary body", name)),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A 3D Tiles tileset.json.
+///
+/// Properties this model doesn't know about are kept in `other` on every
+/// object, so a tileset survives a parse and serialize round trip.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tileset {
+    pub asset: Asset,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geometric_error: Option<f64>,
+    pub root: Tile,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions_used: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions_required: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Asset {
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tileset_version: Option<String>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tile {
+    pub bounding_volume: BoundingVolume,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub viewer_request_volume: Option<BoundingVolume>,
+    pub geometric_error: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refine: Option<Refine>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Vec<f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Content>,
+    /// Multiple contents, from 3D Tiles 1.1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contents: Option<Vec<Content>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub children: Option<Vec<Tile>>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Refine {
+    #[serde(rename = "ADD", alias = "add")]
+    Add,
+    #[serde(rename = "REPLACE", alias = "replace")]
+    Replace,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BoundingVolume {
+    #[serde(rename = "box", default, skip_serializing_if = "Option::is_none")]
+    pub bounding_box: Option<Vec<f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<Vec<f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sphere: Option<Vec<f64>>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Content {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    /// The pre-1.0 name of `uri`, still served by some tileservers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounding_volume: Option<BoundingVolume>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<u32>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl Tileset {
+    pub fn from_json(json: &str) -> Result<Tileset, String> {
+        serde_json::from_str(json).map_err(|e| format!("Invalid tileset JSON: {}", e))
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| format!("Unable to serialize tileset: {}", e))
+    }
+
+    /// Every tile in the tileset, depth first starting at the root.
+    pub fn tiles(&self) -> Vec<&Tile> {
+        let mut tiles = Vec::new();
+        let mut stack = vec![&self.root];
+        while let Some(tile) = stack.pop() {
+            tiles.push(tile);
+            stack.extend(tile.children.iter().flatten().rev());
+        }
+        tiles
+    }
+
+    /// Call `f` on every tile in the tileset, depth first starting at the root.
+    pub fn for_each_tile_mut(&mut self, mut f: impl FnMut(&mut Tile)) {
+        let mut stack = vec![&mut self.root];
+        while let Some(tile) = stack.pop() {
+            f(tile);
+            stack.extend(tile.children.iter_mut().flatten().rev());
+        }
+    }
+
+    /// The URIs of all content in the tileset, in traversal order.
+    pub fn content_uris(&self) -> Vec<&str> {
+        self.tiles().into_iter().flat_map(Tile::contents).filter_map(Content::uri).collect()
+    }
+}
+
+impl Tile {
+    /// The tile's `content` followed by its `contents`.
+    pub fn contents(&self) -> impl Iterator<Item = &Content> {
+        self.content.iter().chain(self.contents.iter().flatten())
+    }
+
+    pub fn contents_mut(&mut self) -> impl Iterator<Item = &mut Content> {
+        self.content.iter_mut().chain(self.contents.iter_mut().flatten())
+    }
+}
+
+impl Content {
+    pub fn uri(&self) -> Option<&str> {
+        self.uri.as_deref().or(self.url.as_deref())
+    }
+}
+
+/// Resolve a content URI found in the tileset at `tileset_path`.
+///
+/// Both `tileset_path` and the result are relative to `base_url`, which must
+/// end with a slash. Returns `None` for URIs that point outside of `base_url`.
+pub fn resolve_content_uri(base_url: &str, tileset_path: &str, uri: &str) -> Option<String> {
+    let base = Url::parse(base_url).ok()?;
+    let resolved = base.join(tileset_path).ok()?.join(uri).ok()?;
+    resolved.as_str().strip_prefix(base.as_str()).map(str::to_string)
+}
+
+/// Whether a relative content path refers to an external tileset rather than a model.
+pub fn is_tileset_path(path: &str) -> bool {
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    path.ends_with(".json")
+}
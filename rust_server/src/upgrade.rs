@@ -0,0 +1,83 @@
+use crate::tileset::Tileset;
+
+pub const UPGRADED_VERSION: &str = "1.1";
+const GLB_EXTENSION: &str = ".glb";
+// Tile formats that are converted to GLB
+const CONVERTIBLE_EXTENSIONS: [&str; 4] = [".b3dm", ".cmpt", ".i3dm", ".pnts"];
+
+// glTF content is part of the core spec in 3D Tiles 1.1
+const CONTENT_GLTF_EXTENSION: &str = "3DTILES_content_gltf";
+
+/// Upgrade a 3D Tiles 1.0 tileset to 1.1.
+///
+/// Legacy `content.url` properties become `content.uri`. Model URIs are left
+/// as they are, since only content served by this server gets its [`glb_uri`],
+/// which [`rewrite_content_uris`](crate::rewrite::rewrite_content_uris) does
+/// once it's resolved. Upgrading an already upgraded tileset is a no-op.
+pub fn upgrade_tileset(tileset: &mut Tileset) {
+    tileset.asset.version = UPGRADED_VERSION.to_string();
+
+    tileset.for_each_tile_mut(|tile| {
+        for content in tile.contents_mut() {
+            if let Some(url) = content.url.take() {
+                content.uri.get_or_insert(url);
+            }
+        }
+    });
+
+    for list in [&mut tileset.extensions_used, &mut tileset.extensions_required] {
+        if let Some(extensions) = list {
+            extensions.retain(|e| e != CONTENT_GLTF_EXTENSION);
+        }
+        if list.as_ref().is_some_and(Vec::is_empty) {
+            *list = None;
+        }
+    }
+    if let Some(extensions) = tileset.other.get_mut("extensions").and_then(|e| e.as_object_mut()) {
+        extensions.remove(CONTENT_GLTF_EXTENSION);
+        if extensions.is_empty() {
+            tileset.other.remove("extensions");
+        }
+    }
+}
+
+/// Upgrade the JSON of a tileset with [`upgrade_tileset`].
+pub fn upgrade_tileset_json(json: &str) -> Result<String, String> {
+    let mut tileset = Tileset::from_json(json)?;
+    upgrade_tileset(&mut tileset);
+    tileset.to_json()
+}
+
+/// The URI a model is served as once converted, e.g. `123model.b3dm` becomes `123model.b3dm.glb`.
+///
+/// The original name is kept so the server can fetch the source model from
+/// upstream. Only tile formats that are converted get a new URI, e.g.
+/// tilesets and glTF content are returned unchanged.
+pub fn glb_uri(uri: &str) -> String {
+    let split = uri.find(['?', '#']).unwrap_or(uri.len());
+    let (path, query) = uri.split_at(split);
+    if !is_convertible_path(path) {
+        return uri.to_string();
+    }
+    format!("{}{}{}", path, GLB_EXTENSION, query)
+}
+
+/// The URI of the source model behind a URI produced by [`glb_uri`].
+///
+/// Other GLB URIs, e.g. `tiles/a.glb`, are GLB content upstream and have none.
+pub fn source_uri(glb_uri: &str) -> Option<String> {
+    let split = glb_uri.find(['?', '#']).unwrap_or(glb_uri.len());
+    let (path, query) = glb_uri.split_at(split);
+    let source = path.strip_suffix(GLB_EXTENSION).filter(|source| is_convertible_path(source))?;
+    Some(format!("{}{}", source, query))
+}
+
+// Paths of the tile formats that are converted, including the extensionless
+// 123model, which the tileserver serves as b3dm or cmpt
+fn is_convertible_path(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    if CONVERTIBLE_EXTENSIONS.iter().any(|extension| name.ends_with(extension)) {
+        return true;
+    }
+    name.strip_suffix("model").is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
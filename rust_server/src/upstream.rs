@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::Rng;
+use reqwest::{
+    blocking::{Client, Response},
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER},
+    StatusCode,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{b3dm, cmpt, glb, i3dm, pnts, source::Source};
+
+/// How often and how patiently failed upstream requests are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt.
+    pub retries: u32,
+    /// The backoff before the first retry, doubled for every further one.
+    pub base_delay: Duration,
+    /// Upper bound of the backoff. A longer `Retry-After` ends the retries.
+    pub max_delay: Duration,
+    /// Consecutive failures after which the circuit breaker of an upstream opens.
+    pub breaker_threshold: u32,
+    /// How long an open circuit breaker fails fast before letting a probe through.
+    pub breaker_cooldown: Duration,
+}
+
+/// The validators of an upstream response, for revalidating it with a conditional request.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn of(response: &Response) -> Validators {
+        let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        Validators { etag: header(ETAG), last_modified: header(LAST_MODIFIED) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Why content couldn't be fetched from an upstream.
+#[derive(Clone, Debug)]
+pub enum FetchError {
+    /// The upstream doesn't have the content.
+    NotFound(String),
+    /// The upstream didn't answer in time.
+    Timeout(String),
+    /// The upstream failed, is unavailable or sent something that isn't valid content.
+    BadGateway(String),
+    /// The content was fetched but couldn't be stored.
+    Cache(String),
+    /// The content isn't cached and upstream isn't contacted in offline mode.
+    Offline(String),
+}
+
+impl FetchError {
+    /// The status to answer the client with.
+    pub fn status(&self) -> u16 {
+        match self {
+            FetchError::NotFound(_) | FetchError::Offline(_) => 404,
+            FetchError::Timeout(_) => 504,
+            FetchError::BadGateway(_) => 502,
+            FetchError::Cache(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::NotFound(e) | FetchError::Timeout(e) | FetchError::BadGateway(e) | FetchError::Cache(e) | FetchError::Offline(e) => f.write_str(e),
+        }
+    }
+}
+
+/// Fetch a tileset, which has to be a successful response holding a JSON object.
+pub fn fetch_tileset(client: &Client, source: &Source, path: &str, policy: &RetryPolicy) -> Result<(String, Validators), FetchError> {
+    let fetched = fetch_tileset_if_modified(client, source, path, &Validators::default(), policy)?;
+    Ok(fetched.unwrap_or_default())
+}
+
+/// Fetch a tileset unless it still matches the validators of the cached copy, in which case it's `None`.
+pub fn fetch_tileset_if_modified(client: &Client, source: &Source, path: &str, validators: &Validators, policy: &RetryPolicy) -> Result<Option<(String, Validators)>, FetchError> {
+    let response = fetch_if_modified(client, source, path, validators, policy)?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let validators = Validators::of(&response);
+    let body = read_body(response, source, path)?;
+    check_tileset(&body).map_err(|e| FetchError::BadGateway(format!("{} from {}", e, source.url_of(path))))?;
+    // check_tileset made sure it's UTF-8
+    Ok(Some((String::from_utf8(body).unwrap_or_default(), validators)))
+}
+
+/// Fetch a tile, which has to be a successful response in one of the supported tile formats.
+pub fn fetch_model(client: &Client, source: &Source, path: &str, policy: &RetryPolicy) -> Result<Vec<u8>, FetchError> {
+    let body = read_body(fetch(client, source, path, policy)?, source, path)?;
+    check_model(&body).map_err(|e| FetchError::BadGateway(format!("{} from {}", e, source.url_of(path))))?;
+    Ok(body)
+}
+
+/// Check that a payload looks like a whole tileset rather than e.g. an HTML error page or a cut off body.
+pub fn check_tileset(bytes: &[u8]) -> Result<(), String> {
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return Err("Tileset isn't valid UTF-8".to_string());
+    };
+    let text = text.trim_start_matches('\u{feff}').trim();
+    if !text.starts_with('{') {
+        return Err("Tileset isn't a JSON object".to_string());
+    }
+    if !text.ends_with('}') {
+        return Err("Tileset is truncated".to_string());
+    }
+    Ok(())
+}
+
+/// Check that a payload starts with the magic of a tile format that can be converted, and has the length its header gives.
+pub fn check_model(bytes: &[u8]) -> Result<(), String> {
+    // Every format starts with its magic, a version and the byte length
+    let (Some(magic), Some(byte_length)) = (bytes.get(0..4), bytes.get(8..12)) else {
+        return Err("Model is too short to hold a header".to_string());
+    };
+    if ![b3dm::MAGIC, cmpt::MAGIC, i3dm::MAGIC, pnts::MAGIC, glb::MAGIC].iter().any(|m| magic == &m[..]) {
+        return Err(format!("Model has the unknown magic {:?}", String::from_utf8_lossy(magic)));
+    }
+    let byte_length = u32::from_le_bytes([byte_length[0], byte_length[1], byte_length[2], byte_length[3]]) as usize;
+    if byte_length > bytes.len() {
+        return Err(format!("Model is truncated, it has {} of {} bytes", bytes.len(), byte_length));
+    }
+    Ok(())
+}
+
+fn read_body(response: Response, source: &Source, path: &str) -> Result<Vec<u8>, FetchError> {
+    match response.bytes() {
+        Ok(bytes) => Ok(bytes.to_vec()),
+        Err(e) if e.is_timeout() => Err(FetchError::Timeout(format!("Timed out reading {}: {}", source.url_of(path), e))),
+        Err(e) => Err(FetchError::BadGateway(format!("Failed to read {}: {}", source.url_of(path), e))),
+    }
+}
+
+/// Fetch a path of a source, retrying timeouts, connection errors, 5xx and 429.
+///
+/// The backoff is exponential with jitter, so clients that failed together
+/// don't retry together, and a `Retry-After` from the upstream is respected.
+/// Every upstream has a circuit breaker that fails requests immediately
+/// while the upstream is known to be down.
+///
+/// Only successful responses are returned, anything else is an error.
+pub fn fetch(client: &Client, source: &Source, path: &str, policy: &RetryPolicy) -> Result<Response, FetchError> {
+    fetch_if_modified(client, source, path, &Validators::default(), policy)
+}
+
+/// Like [`fetch`], but a conditional request when there are validators, which may be answered with 304.
+pub fn fetch_if_modified(client: &Client, source: &Source, path: &str, validators: &Validators, policy: &RetryPolicy) -> Result<Response, FetchError> {
+    let breaker = circuit_breaker(&source.url);
+    let url = source.url_of(path);
+    let mut last_error = FetchError::BadGateway(format!("Failed to fetch from {}", url));
+    for attempt in 0..=policy.retries {
+        if !breaker.allow_request() {
+            return Err(FetchError::BadGateway(format!("Upstream {} is unavailable, not fetching {}", source.url, url)));
+        }
+
+        let mut request = source.get(client, path);
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let delay = match request.send() {
+            Ok(response) if is_retryable(response.status()) => {
+                breaker.record_failure(policy);
+                last_error = FetchError::BadGateway(format!("Upstream answered {} for {}", response.status(), url));
+                retry_after(&response).unwrap_or_else(|| backoff(policy, attempt))
+            }
+            Ok(response) => {
+                // Any other answer means the upstream is up, even if it doesn't have the file
+                breaker.record_success();
+                let status = response.status();
+                return match status {
+                    _ if status.is_success() => Ok(response),
+                    StatusCode::NOT_MODIFIED if !validators.is_empty() => Ok(response),
+                    StatusCode::NOT_FOUND | StatusCode::GONE => Err(FetchError::NotFound(format!("Upstream answered {} for {}", status, url))),
+                    _ => Err(FetchError::BadGateway(format!("Upstream answered {} for {}", status, url))),
+                };
+            }
+            Err(e) if e.is_timeout() || e.is_connect() || e.is_request() => {
+                breaker.record_failure(policy);
+                last_error = if e.is_timeout() {
+                    FetchError::Timeout(format!("Timed out fetching {}: {}", url, e))
+                } else {
+                    FetchError::BadGateway(format!("Failed to fetch from {}: {}", url, e))
+                };
+                backoff(policy, attempt)
+            }
+            Err(e) => {
+                // e.g. a redirect loop or an invalid URL, which say nothing about whether the upstream is up
+                breaker.release();
+                return Err(FetchError::BadGateway(format!("Failed to fetch from {}: {}", url, e)));
+            }
+        };
+
+        // Waiting is pointless once the breaker fails the next attempt anyway
+        if attempt == policy.retries || breaker.is_open() {
+            break;
+        }
+        if delay > policy.max_delay {
+            println!("Not retrying {} as the upstream asked to wait {:?}", url, delay);
+            break;
+        }
+        println!("{}, retrying in {:?}", last_error, delay);
+        thread::sleep(delay);
+    }
+    Err(last_error)
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// Half of the exponential delay is fixed and the other half random
+fn backoff(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let exponential = policy.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(policy.max_delay);
+    let half = exponential / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+// Retry-After is either a number of seconds or an HTTP-date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+#[derive(Debug)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A single probe request is in flight after the cooldown.
+    HalfOpen,
+}
+
+/// Fails requests fast while an upstream is down.
+///
+/// After `breaker_threshold` consecutive failures the breaker opens for
+/// `breaker_cooldown`. Then one probe is let through, which closes the
+/// breaker if it succeeds and opens it again if it fails.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker { state: Mutex::new(BreakerState::Closed { failures: 0 }) }
+    }
+
+    pub fn allow_request(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                *state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen => false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), BreakerState::Closed { .. })
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(*state, BreakerState::HalfOpen) {
+            println!("Upstream is reachable again, closing its circuit breaker");
+        }
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    /// Give up a request without an outcome, so a probe that was let through doesn't block the next one.
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(*state, BreakerState::HalfOpen) {
+            *state = BreakerState::Open { until: Instant::now() };
+        }
+    }
+
+    pub fn record_failure(&self, policy: &RetryPolicy) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            BreakerState::HalfOpen => policy.breaker_threshold,
+            // Requests that started before the breaker opened
+            BreakerState::Open { .. } => return,
+        };
+        *state = if failures >= policy.breaker_threshold {
+            println!("Opening circuit breaker for {:?} after {} failures", policy.breaker_cooldown, failures);
+            BreakerState::Open { until: Instant::now() + policy.breaker_cooldown }
+        } else {
+            BreakerState::Closed { failures }
+        };
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker::new()
+    }
+}
+
+// Sources are created per request, so their breakers are kept by base URL
+fn circuit_breaker(url: &str) -> Arc<CircuitBreaker> {
+    static BREAKERS: OnceLock<Mutex<HashMap<String, Arc<CircuitBreaker>>>> = OnceLock::new();
+    let breakers = BREAKERS.get_or_init(Default::default);
+    Arc::clone(breakers.lock().unwrap().entry(url.to_string()).or_default())
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use tileset_conversion_server::{
+    conditional::{self, http_date},
+    http::{self, Request},
+};
+
+const ETAG: &str = "\"abc\"";
+
+fn request(headers: &[(&str, &str)]) -> Request {
+    let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    http::read_request(&mut format!("GET /tileset.json HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap()
+}
+
+#[test]
+fn matches_if_none_match_weakly() {
+    let cases = [
+        ("\"abc\"", true),
+        ("W/\"abc\"", true),
+        ("\"xyz\", W/\"abc\"", true),
+        ("*", true),
+        ("\"xyz\"", false),
+        ("\"ab\"", false),
+    ];
+    for (if_none_match, not_modified) in cases {
+        assert_eq!(conditional::is_not_modified(&request(&[("If-None-Match", if_none_match)]), ETAG, None), not_modified, "{}", if_none_match);
+    }
+    assert!(conditional::is_not_modified(&request(&[("If-None-Match", "\"abc\"")]), "W/\"abc\"", None));
+    assert!(!conditional::is_not_modified(&request(&[]), ETAG, None));
+}
+
+#[test]
+fn compares_modification_dates_in_whole_seconds() {
+    let modified = UNIX_EPOCH + Duration::from_millis(1_445_412_480_750);
+    let second = UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+    let since = |time| request(&[("If-Modified-Since", &http_date(time))]);
+
+    // The date the client got was truncated, and is still current
+    assert!(conditional::is_not_modified(&since(second), ETAG, Some(modified)));
+    assert!(conditional::is_not_modified(&since(second + Duration::from_secs(60)), ETAG, Some(modified)));
+    assert!(!conditional::is_not_modified(&since(second - Duration::from_secs(1)), ETAG, Some(modified)));
+    assert!(!conditional::is_not_modified(&since(second), ETAG, None));
+    assert!(!conditional::is_not_modified(&request(&[("If-Modified-Since", "yesterday")]), ETAG, Some(modified)));
+
+    // If-None-Match takes precedence, whatever the date says
+    let both = |etag: &str, time| request(&[("If-None-Match", etag), ("If-Modified-Since", &http_date(time))]);
+    assert!(!conditional::is_not_modified(&both("\"xyz\"", second), ETAG, Some(modified)));
+    assert!(conditional::is_not_modified(&both(ETAG, second - Duration::from_secs(1)), ETAG, Some(modified)));
+
+    let range = |if_range: &str| request(&[("Range", "bytes=0-9"), ("If-Range", if_range)]);
+    assert!(conditional::if_range_matches(&range(&http_date(second)), ETAG, Some(modified)));
+    assert!(!conditional::if_range_matches(&range(&http_date(second + Duration::from_secs(1))), ETAG, Some(modified)));
+    assert!(!conditional::if_range_matches(&range(&http_date(second)), ETAG, None));
+}
+
+#[test]
+fn matches_if_range_strongly() {
+    let range = |if_range: &str| request(&[("Range", "bytes=0-9"), ("If-Range", if_range)]);
+
+    assert!(conditional::if_range_matches(&request(&[("Range", "bytes=0-9")]), ETAG, None));
+    assert!(conditional::if_range_matches(&range("\"abc\""), ETAG, None));
+    assert!(!conditional::if_range_matches(&range("\"xyz\""), ETAG, None));
+    assert!(!conditional::if_range_matches(&range("W/\"abc\""), ETAG, None));
+    assert!(!conditional::if_range_matches(&range("W/\"abc\""), "W/\"abc\"", None));
+    // A list or a wildcard isn't a validator here
+    assert!(!conditional::if_range_matches(&range("*"), ETAG, None));
+}
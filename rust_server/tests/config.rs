@@ -0,0 +1,89 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use tileset_conversion_server::config::{self, Config};
+
+// The environment is shared by the tests, which run in parallel
+static ENV: Mutex<()> = Mutex::new(());
+
+fn lock_env() -> MutexGuard<'static, ()> {
+    ENV.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("config-test-{}-{}.toml", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn load(path: &Path, args: &[&str]) -> Result<Config, String> {
+    let mut all = vec!["--config".to_string(), path.to_str().unwrap().to_string()];
+    all.extend(args.iter().map(|a| a.to_string()));
+    Config::load(all)
+}
+
+#[test]
+fn takes_the_command_line_over_the_environment_over_the_file() {
+    let _env = lock_env();
+    let path = config_file("precedence", "port = 1000\nthreads = 2\nretries = 7\ncache_dir = \"from-file\"\n");
+    env::set_var(config::env_name("port"), "2000");
+    env::set_var(config::env_name("threads"), "3");
+    // Only prefixed variables are options
+    env::set_var("RETRIES", "9");
+
+    let loaded = load(&path, &["--port", "3000", "--cors-allowed-origins=https://a.example.com, *"]);
+    env::remove_var(config::env_name("port"));
+    env::remove_var(config::env_name("threads"));
+    env::remove_var("RETRIES");
+    fs::remove_file(&path).unwrap();
+
+    let config = loaded.unwrap();
+    assert_eq!((config.port, config.threads, config.retries, config.cache_dir.as_str()), (3000, 3, 7, "from-file"));
+    assert_eq!(config.cors_allowed_origins, ["https://a.example.com", "*"]);
+    // Everything else has its default
+    assert_eq!(config.keep_alive_timeout_secs, Config::default().keep_alive_timeout_secs);
+    assert_eq!(config::env_name("api_key"), "TILESET_SERVER_API_KEY");
+}
+
+#[test]
+fn rejects_invalid_values() {
+    let path = config_file("invalid", "");
+    let invalid = [
+        vec!["--threads", "0"],
+        vec!["--keep-alive-timeout-secs", "0"],
+        vec!["--max-requests-per-connection=0"],
+        vec!["--breaker-threshold", "0"],
+        vec!["--port", "70000"],
+        vec!["--offline", "maybe"],
+        vec!["--retries", "-1"],
+        vec!["--converter", "blender"],
+        vec!["--bind", ","],
+        vec!["--unknown", "1"],
+        vec!["--port"],
+        vec!["port"],
+    ];
+    for args in invalid {
+        assert!(load(&path, &args).is_err(), "{:?}", args);
+    }
+    fs::remove_file(&path).unwrap();
+
+    let path = config_file("unknown", "prot = 7878\n");
+    assert!(load(&path, &[]).unwrap_err().starts_with("Invalid config"));
+    fs::remove_file(&path).unwrap();
+
+    let path = config_file("source", "[sources.\"a/b\"]\nurl = \"https://tiles.example.com/\"\n");
+    assert!(load(&path, &[]).is_err());
+    fs::remove_file(&path).unwrap();
+
+    // Errors in the environment name the variable
+    let _env = lock_env();
+    let path = config_file("env", "");
+    env::set_var(config::env_name("retries"), "many");
+    let loaded = load(&path, &[]);
+    env::remove_var(config::env_name("retries"));
+    fs::remove_file(&path).unwrap();
+    assert!(loaded.unwrap_err().contains("TILESET_SERVER_RETRIES"));
+}
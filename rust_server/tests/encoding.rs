@@ -0,0 +1,30 @@
+use tileset_conversion_server::encoding::{self, Encoding};
+
+#[test]
+fn negotiates_the_encoding_with_the_highest_quality() {
+    let cases = [
+        (None, Encoding::Identity),
+        (Some(""), Encoding::Identity),
+        (Some("gzip"), Encoding::Gzip),
+        (Some("x-gzip"), Encoding::Gzip),
+        (Some("GZIP, deflate"), Encoding::Gzip),
+        // Brotli wins a tie
+        (Some("gzip, deflate, br"), Encoding::Brotli),
+        (Some("gzip;q=1.0, br;q=1"), Encoding::Brotli),
+        (Some("gzip;q=0.8, br;q=0.5"), Encoding::Gzip),
+        (Some("br ; q=0.9, gzip;q=0.1"), Encoding::Brotli),
+        // q=0 refuses a coding
+        (Some("br;q=0, gzip;q=0"), Encoding::Identity),
+        (Some("br;q=0"), Encoding::Identity),
+        (Some("*"), Encoding::Brotli),
+        (Some("*;q=0.5, br;q=0"), Encoding::Gzip),
+        (Some("*;q=0"), Encoding::Identity),
+        // Refusing identity still gets a coding the client accepts, and identity when it accepts none
+        (Some("gzip;q=0.5, identity;q=0"), Encoding::Gzip),
+        (Some("identity;q=0"), Encoding::Identity),
+        (Some("identity"), Encoding::Identity),
+    ];
+    for (accept_encoding, expected) in cases {
+        assert_eq!(encoding::negotiate(accept_encoding), expected, "{:?}", accept_encoding);
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::{BufReader, Cursor};
+
+use tileset_conversion_server::{
+    cors::Cors,
+    http::{self, ByteRange, Request, RequestError, Response},
+};
+
+fn read(raw: &[u8]) -> Result<Request, RequestError> {
+    http::read_request(&mut BufReader::new(Cursor::new(raw.to_vec())))
+}
+
+fn is_malformed(result: Result<Request, RequestError>) -> bool {
+    matches!(result, Err(RequestError::Malformed(_)))
+}
+
+#[test]
+fn parses_method_path_query_and_headers() {
+    let request = read(b"GET /sub%20dir/a+b.b3dm?tile=2&name=a+b%26c&flag HTTP/1.1\r\nHost: example.com\r\nIf-None-Match:  \"abc\" \r\n\r\n").unwrap();
+
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.version, "HTTP/1.1");
+    // A plus is only a space in the query
+    assert_eq!(request.path, "/sub dir/a+b.b3dm");
+    assert_eq!(request.query_param("tile"), Some("2"));
+    assert_eq!(request.query_param("name"), Some("a b&c"));
+    assert_eq!(request.query_param("flag"), Some(""));
+    assert_eq!(request.query_param("missing"), None);
+    assert_eq!(request.header("host"), Some("example.com"));
+    assert_eq!(request.header("IF-NONE-MATCH"), Some("\"abc\""));
+}
+
+#[test]
+fn keeps_the_query_as_it_was_sent() {
+    let request = read(b"GET /a.b3dm?tile=1&v=a%2Fb+c&&instancing=baked&sig HTTP/1.1\r\n\r\n").unwrap();
+
+    assert_eq!(request.raw_query, "tile=1&v=a%2Fb+c&&instancing=baked&sig");
+    assert_eq!(request.query_string(&[]), "?tile=1&v=a%2Fb+c&instancing=baked&sig");
+    assert_eq!(request.query_string(&["tile", "instancing"]), "?v=a%2Fb+c&sig");
+    assert_eq!(request.query_string(&["tile", "v", "instancing", "sig"]), "");
+    assert_eq!(read(b"GET /a.b3dm HTTP/1.1\r\n\r\n").unwrap().query_string(&[]), "");
+}
+
+#[test]
+fn accepts_bare_lf_and_leading_empty_lines() {
+    let request = read(b"\r\n\nGET /tileset.json HTTP/1.0\nAccept: */*\n\n").unwrap();
+
+    assert_eq!(request.path, "/tileset.json");
+    assert_eq!(request.version, "HTTP/1.0");
+    assert_eq!(request.header("Accept"), Some("*/*"));
+}
+
+#[test]
+fn reads_bodies_and_leaves_the_next_request() {
+    let mut reader = BufReader::new(Cursor::new(b"GET /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\n".to_vec()));
+
+    let first = http::read_request(&mut reader).unwrap();
+    assert_eq!(first.body, b"hello");
+    let second = http::read_request(&mut reader).unwrap();
+    assert_eq!(second.path, "/b");
+    assert!(matches!(http::read_request(&mut reader), Err(RequestError::Closed)));
+}
+
+#[test]
+fn rejects_malformed_requests() {
+    let too_large = format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", "a".repeat(64 * 1024));
+    let cases: [(&str, &[u8]); 15] = [
+        ("missing version", b"GET /\r\n\r\n"),
+        ("extra request line part", b"GET / HTTP/1.1 x\r\n\r\n"),
+        ("lowercase method", b"get / HTTP/1.1\r\n\r\n"),
+        ("unsupported version", b"GET / HTTP/2\r\n\r\n"),
+        ("absolute-form target", b"GET http://example.com/ HTTP/1.1\r\n\r\n"),
+        ("header without colon", b"GET / HTTP/1.1\r\nHost\r\n\r\n"),
+        ("space before colon", b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"),
+        ("truncated percent-encoding", b"GET /a%4 HTTP/1.1\r\n\r\n"),
+        ("invalid percent-encoding", b"GET /a%zz HTTP/1.1\r\n\r\n"),
+        ("invalid percent-encoding in query", b"GET /a?b=%g0 HTTP/1.1\r\n\r\n"),
+        ("percent-encoded invalid UTF-8", b"GET /a%ff HTTP/1.1\r\n\r\n"),
+        ("chunked body", b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+        ("invalid Content-Length", b"GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+        ("closed in the headers", b"GET / HTTP/1.1\r\nHost: a\r\n"),
+        ("head over the limit", too_large.as_bytes()),
+    ];
+    for (name, raw) in cases {
+        assert!(is_malformed(read(raw)), "{}", name);
+    }
+}
+
+#[test]
+fn tells_closed_connections_apart() {
+    assert!(matches!(read(b""), Err(RequestError::Closed)));
+    assert!(matches!(read(b"\r\n"), Err(RequestError::Closed)));
+    // A request line without its line ending is malformed rather than closed
+    assert!(is_malformed(read(b"GET / HTTP/1.1")));
+}
+
+#[test]
+fn selects_requested_byte_ranges() {
+    let cases = [
+        ("no header", None, 100, ByteRange::Full),
+        ("other unit", Some("items=0-1"), 100, ByteRange::Full),
+        ("several ranges", Some("bytes=0-1,5-6"), 100, ByteRange::Full),
+        ("bounded", Some("bytes=10-19"), 100, ByteRange::Partial { start: 10, end: 19 }),
+        ("end past the body", Some("bytes=90-200"), 100, ByteRange::Partial { start: 90, end: 99 }),
+        ("open-ended", Some("bytes=40-"), 100, ByteRange::Partial { start: 40, end: 99 }),
+        ("suffix", Some("bytes=-30"), 100, ByteRange::Partial { start: 70, end: 99 }),
+        ("suffix longer than the body", Some("bytes=-300"), 100, ByteRange::Partial { start: 0, end: 99 }),
+        ("start past the body", Some("bytes=100-"), 100, ByteRange::Unsatisfiable),
+        ("empty suffix", Some("bytes=-0"), 100, ByteRange::Unsatisfiable),
+        ("suffix of an empty body", Some("bytes=-5"), 0, ByteRange::Unsatisfiable),
+        ("start after end", Some("bytes=20-10"), 100, ByteRange::Full),
+        ("not numbers", Some("bytes=a-b"), 100, ByteRange::Full),
+    ];
+    for (name, range, length, expected) in cases {
+        assert_eq!(http::byte_range(range, length), expected, "{}", name);
+    }
+}
+
+#[test]
+fn writes_partial_file_bodies() {
+    let path = std::env::temp_dir().join(format!("http-test-{}.bin", std::process::id()));
+    std::fs::write(&path, b"0123456789").unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+
+    let mut written = Vec::new();
+    Response::new(206).with_header("Content-Range", "bytes 2-5/10").with_file(file, 2, 4).write_to(&mut written).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let written = String::from_utf8(written).unwrap();
+    assert!(written.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(written.contains("Content-Range: bytes 2-5/10\r\n"));
+    assert!(written.ends_with("Content-Length: 4\r\n\r\n2345"));
+}
+
+#[test]
+fn leaves_the_length_out_of_not_modified_responses() {
+    let mut written = Vec::new();
+    Response::new(304).with_header("ETag", "\"a\"").write_to(&mut written).unwrap();
+
+    assert_eq!(String::from_utf8(written).unwrap(), "HTTP/1.1 304 Not Modified\r\nETag: \"a\"\r\n\r\n");
+}
+
+#[test]
+fn writes_heads_with_the_length_of_the_body() {
+    let mut written = Vec::new();
+    Response::new(200).with_header("Content-Type", "application/json").with_body(b"{}".to_vec()).write_head_to(&mut written).unwrap();
+
+    assert_eq!(String::from_utf8(written).unwrap(), "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n");
+}
+
+// All values of a header, as a response may have it more than once
+fn headers<'a>(response: &'a Response, name: &str) -> Vec<&'a str> {
+    response.headers.iter().filter(|(n, _)| n == name).map(|(_, v)| v.as_str()).collect()
+}
+
+fn origins(origins: &[&str]) -> Cors {
+    Cors::new(&origins.iter().map(|o| o.to_string()).collect::<Vec<String>>())
+}
+
+#[test]
+fn lists_each_header_a_response_varies_by_once() {
+    let response = Response::new(200).with_vary("Accept-Encoding").with_vary("Origin").with_vary("origin");
+    assert_eq!(headers(&response, "Vary"), ["Accept-Encoding, Origin"]);
+}
+
+#[test]
+fn allows_origins_on_the_allowlist() {
+    let cors = origins(&["https://qa.example.com/", "http://localhost:8080"]);
+    let from = |origin: &str| read(format!("GET /tileset.json HTTP/1.1\r\nOrigin: {}\r\n\r\n", origin).as_bytes()).unwrap();
+
+    let response = cors.apply(&from("https://qa.example.com"), Response::new(200).with_vary("Accept-Encoding"));
+    assert_eq!(headers(&response, "Access-Control-Allow-Origin"), ["https://qa.example.com"]);
+    assert_eq!(headers(&response, "Access-Control-Expose-Headers").len(), 1);
+    assert_eq!(headers(&response, "Vary"), ["Accept-Encoding, Origin"]);
+
+    // Other origins get no CORS headers, but caches still have to tell them apart
+    let response = cors.apply(&from("https://evil.example.com"), Response::new(200).with_vary("Origin"));
+    assert!(headers(&response, "Access-Control-Allow-Origin").is_empty());
+    assert_eq!(headers(&response, "Vary"), ["Origin"]);
+
+    // Without an allowlist there is no CORS at all
+    let response = origins(&[" "]).apply(&from("https://qa.example.com"), Response::new(200));
+    assert!(response.headers.is_empty());
+}
+
+#[test]
+fn allows_any_origin_with_a_wildcard() {
+    let cors = origins(&["https://qa.example.com", "*"]);
+    let request = read(b"GET /tileset.json HTTP/1.1\r\nOrigin: https://any.example.com\r\n\r\n").unwrap();
+
+    let response = cors.apply(&request, Response::new(200));
+    assert_eq!(headers(&response, "Access-Control-Allow-Origin"), ["*"]);
+    // The response is the same for every origin
+    assert!(headers(&response, "Vary").is_empty());
+}
+
+#[test]
+fn answers_preflights_for_allowed_origins_and_methods() {
+    let cors = origins(&["https://qa.example.com"]);
+    let preflight = |origin: &str, method: &str| {
+        let raw = format!("OPTIONS /tileset.json HTTP/1.1\r\nOrigin: {}\r\nAccess-Control-Request-Method: {}\r\nAccess-Control-Request-Headers: range, if-none-match\r\n\r\n", origin, method);
+        cors.preflight(&read(raw.as_bytes()).unwrap())
+    };
+
+    for method in ["GET", "HEAD"] {
+        let response = preflight("https://qa.example.com", method);
+        assert_eq!(response.status, 204);
+        assert_eq!(headers(&response, "Access-Control-Allow-Origin"), ["https://qa.example.com"]);
+        assert_eq!(headers(&response, "Access-Control-Allow-Methods"), ["GET, HEAD, OPTIONS"]);
+        assert_eq!(headers(&response, "Access-Control-Allow-Headers"), ["range, if-none-match"]);
+        assert_eq!(headers(&response, "Access-Control-Max-Age"), ["86400"]);
+        assert_eq!(headers(&response, "Vary"), ["Origin"]);
+    }
+
+    // Refused preflights and plain OPTIONS requests only get the allowed methods
+    let plain = cors.preflight(&read(b"OPTIONS /tileset.json HTTP/1.1\r\n\r\n").unwrap());
+    for response in [preflight("https://evil.example.com", "GET"), preflight("https://qa.example.com", "DELETE"), plain] {
+        assert_eq!(response.status, 204);
+        let names: Vec<&str> = response.headers.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["Allow"]);
+        assert_eq!(headers(&response, "Allow"), ["GET, HEAD, OPTIONS"]);
+    }
+}
@@ -0,0 +1,26 @@
+use std::{sync::mpsc, time::Duration};
+
+use tileset_conversion_server::ThreadPool;
+
+#[test]
+fn counts_jobs_waiting_for_a_worker() {
+    let pool = ThreadPool::new(1);
+    let backlog = pool.backlog();
+    let (started, is_started) = mpsc::channel();
+    let (release, is_released) = mpsc::channel::<()>();
+    let (done, is_done) = mpsc::channel();
+
+    pool.execute(move || {
+        started.send(()).unwrap();
+        is_released.recv().unwrap();
+    });
+    is_started.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(backlog.is_empty());
+
+    pool.execute(move || done.send(()).unwrap());
+    assert!(!backlog.is_empty());
+
+    release.send(()).unwrap();
+    is_done.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(backlog.is_empty());
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use tileset_conversion_server::retention::{MemoryCache, MemoryGlb};
+
+fn glb(size: usize) -> MemoryGlb {
+    MemoryGlb { bytes: Arc::new(vec![0; size]), etag: format!("\"{}\"", size), source_blob: "blob".to_string(), last_modified: None }
+}
+
+fn cached(cache: &MemoryCache, keys: &[&str]) -> Vec<String> {
+    keys.iter().filter(|key| cache.get(key).is_some()).map(|key| key.to_string()).collect()
+}
+
+#[test]
+fn evicts_the_least_recently_used_glbs_to_stay_below_the_limit() {
+    let cache = MemoryCache::new(100);
+    cache.insert("a", glb(40));
+    cache.insert("b", glb(30));
+    cache.insert("c", glb(30));
+    // Using a makes b the least recently used
+    assert_eq!(cache.get("a").unwrap().etag, "\"40\"");
+
+    cache.insert("d", glb(20));
+    assert_eq!(cached(&cache, &["a", "c", "d", "b"]), ["a", "c", "d"]);
+
+    // As many are evicted as it takes, oldest first
+    cache.insert("e", glb(70));
+    assert_eq!(cached(&cache, &["a", "c", "d", "e"]), ["d", "e"]);
+}
+
+#[test]
+fn counts_replaced_glbs_once_and_skips_those_larger_than_the_cache() {
+    let cache = MemoryCache::new(100);
+    cache.insert("a", glb(60));
+    cache.insert("a", glb(50));
+    cache.insert("b", glb(50));
+    assert_eq!(cached(&cache, &["a", "b"]), ["a", "b"]);
+    assert_eq!(cache.get("a").unwrap().etag, "\"50\"");
+
+    // Too large to keep, so it evicts nothing and drops what it replaced
+    cache.insert("b", glb(101));
+    assert_eq!(cached(&cache, &["a", "b"]), ["a"]);
+    cache.insert("c", glb(50));
+    assert_eq!(cached(&cache, &["a", "c"]), ["a", "c"]);
+
+    let empty = MemoryCache::new(0);
+    empty.insert("a", glb(1));
+    assert!(empty.get("a").is_none());
+}
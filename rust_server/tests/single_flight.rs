@@ -0,0 +1,77 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Barrier,
+    },
+    thread,
+    time::Duration,
+};
+
+use tileset_conversion_server::single_flight::SingleFlight;
+
+const THREADS: usize = 8;
+
+#[test]
+fn runs_concurrent_work_on_the_same_key_once() {
+    let flight = Arc::new(SingleFlight::new());
+    let runs = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let (flight, runs, barrier) = (Arc::clone(&flight), Arc::clone(&runs), Arc::clone(&barrier));
+            thread::spawn(move || {
+                barrier.wait();
+                flight.run("tiles/1model.b3dm", || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    // Long enough for the others to find it running
+                    thread::sleep(Duration::from_millis(200));
+                    i
+                })
+            })
+        })
+        .collect();
+    let results: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert!(results.iter().all(|&r| r == results[0]), "{:?}", results);
+
+    // Other keys and later calls run their own work
+    assert_eq!(flight.run("tiles/2model.b3dm", || THREADS), THREADS);
+    assert_eq!(flight.run("tiles/1model.b3dm", || THREADS), THREADS);
+}
+
+#[test]
+fn lets_waiters_do_the_work_when_the_leader_panics() {
+    let flight = Arc::new(SingleFlight::new());
+    let (started, is_started) = mpsc::channel();
+    let (release, is_released) = mpsc::channel::<()>();
+
+    let leader = {
+        let flight = Arc::clone(&flight);
+        thread::spawn(move || {
+            flight.run("tiles/1model.b3dm", || {
+                started.send(()).unwrap();
+                is_released.recv().unwrap();
+                panic!("conversion failed");
+            })
+        })
+    };
+    is_started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let (done, is_done) = mpsc::channel();
+    for i in 1..THREADS {
+        let (flight, done) = (Arc::clone(&flight), done.clone());
+        thread::spawn(move || done.send(flight.run("tiles/1model.b3dm", || i)).unwrap());
+    }
+    // Let the waiters find the leader running before it panics
+    thread::sleep(Duration::from_millis(100));
+    release.send(()).unwrap();
+    assert!(leader.join().is_err());
+
+    let mut results: Vec<usize> = (1..THREADS).map(|_| is_done.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+    results.sort();
+    assert_eq!(results, (1..THREADS).collect::<Vec<usize>>());
+    // The key isn't left running either
+    assert_eq!(flight.run("tiles/1model.b3dm", || 0), 0);
+}
@@ -0,0 +1,281 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use tileset_conversion_server::{
+    convert::{self, Converter},
+    freshness::{self, Revalidation},
+    migrate,
+    source::{Auth, Source},
+    store::{self, Key, Kind, Store},
+    upstream::{RetryPolicy, Validators},
+};
+
+// A cache dir of its own for each test, as they run in parallel
+fn temp_cache_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("store-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn key(url: &str) -> Key {
+    Key { namespace: String::new(), url: url.to_string(), variant: String::new() }
+}
+
+fn write(path: &Path, contents: &[u8]) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+// accessed_at has a resolution of milliseconds
+fn tick() {
+    thread::sleep(Duration::from_millis(5));
+}
+
+#[test]
+fn puts_and_gets_content_addressed_entries() {
+    let dir = temp_cache_dir("put");
+    let store = Store::open(dir.to_str().unwrap()).unwrap();
+    let validators = Validators { etag: Some("\"v1\"".to_string()), last_modified: None };
+
+    let a = store.put(&key("a"), Kind::Models, "application/octet-stream", b"same", &validators, "p").unwrap();
+    let b = store.put(&key("b"), Kind::Models, "application/octet-stream", b"same", &Validators::default(), "").unwrap();
+    assert_eq!(a.blob, b.blob);
+    assert_eq!(fs::read(&a.path).unwrap(), b"same");
+    assert_eq!(store.size(Kind::Models), 8);
+
+    let entry = store.get(&key("a")).unwrap();
+    assert_eq!((entry.kind, entry.size, entry.validators, entry.params.as_str()), (Kind::Models, 4, validators, "p"));
+    assert!(store.get(&key("a").variant("glb")).is_none());
+
+    // The blob is shared, so it stays until the last entry is gone
+    store.remove(&key("a"));
+    assert!(store.get(&key("a")).is_none());
+    assert!(b.path.exists());
+    store.remove(&key("b"));
+    assert!(!b.path.exists());
+
+    let temp = store.temp_path();
+    write(&temp, b"from a file");
+    let entry = store.put_file(&key("c"), Kind::Glbs, "model/gltf-binary", &temp, &Validators::default(), "").unwrap();
+    assert!(!temp.exists());
+    assert_eq!(fs::read(&entry.path).unwrap(), b"from a file");
+    assert_eq!(store.get(&key("c")).unwrap().blob, entry.blob);
+
+    // Replacing content removes the blob it had
+    let replaced = store.put(&key("c"), Kind::Glbs, "model/gltf-binary", b"new", &Validators::default(), "").unwrap();
+    assert!(!entry.path.exists());
+    assert!(replaced.path.exists());
+
+    // An entry whose blob went missing is dropped
+    fs::remove_file(&replaced.path).unwrap();
+    assert!(store.get(&key("c")).is_none());
+    assert_eq!(store.size(Kind::Glbs), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn evicts_least_recently_used_entries() {
+    let dir = temp_cache_dir("evict");
+    let store = Store::open(dir.to_str().unwrap()).unwrap();
+    for url in ["a", "b", "c"] {
+        store.put(&key(url), Kind::Models, "application/octet-stream", url.repeat(10).as_bytes(), &Validators::default(), "").unwrap();
+        tick();
+    }
+    store.put(&key("t"), Kind::Tilesets, "application/json", b"{}", &Validators::default(), "").unwrap();
+    // Using a makes b the least recently used
+    store.get(&key("a")).unwrap();
+    tick();
+
+    assert_eq!(store.evict(Kind::Models, 20, None), 1);
+    assert!(store.get(&key("b")).is_none());
+    assert!(store.get(&key("a")).is_some());
+    assert!(store.get(&key("c")).is_some());
+    // Other kinds have their own quota
+    assert!(store.get(&key("t")).is_some());
+
+    // The entry that's kept stays even when it's over the quota on its own
+    assert_eq!(store.evict(Kind::Models, 0, Some(&key("c"))), 1);
+    assert!(store.get(&key("a")).is_none());
+    assert!(store.get(&key("c")).is_some());
+    assert_eq!(store.size(Kind::Models), 10);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn keeps_running_totals_of_the_sizes() {
+    let dir = temp_cache_dir("sizes");
+    let store = Store::open(dir.to_str().unwrap()).unwrap();
+    store.put(&key("a"), Kind::Models, "application/octet-stream", &[1; 10], &Validators::default(), "").unwrap();
+    store.put(&key("b"), Kind::Models, "application/octet-stream", &[2; 5], &Validators::default(), "").unwrap();
+    store.put(&key("t"), Kind::Tilesets, "application/json", b"{}", &Validators::default(), "").unwrap();
+    // Replacing content counts the new size instead of the old one
+    store.put(&key("a"), Kind::Models, "application/octet-stream", &[3; 4], &Validators::default(), "").unwrap();
+    assert_eq!((store.size(Kind::Models), store.size(Kind::Tilesets), store.size(Kind::Glbs)), (9, 2, 0));
+    store.remove(&key("b"));
+    assert_eq!(store.size(Kind::Models), 4);
+    drop(store);
+
+    // An index written before the totals were kept gets them on open
+    let connection = rusqlite::Connection::open(dir.join(store::INDEX_FILE)).unwrap();
+    connection.execute_batch("DROP TRIGGER entries_inserted; DROP TRIGGER entries_deleted; DROP TRIGGER entries_updated; DROP TABLE kind_sizes;").unwrap();
+    drop(connection);
+    let store = Store::open(dir.to_str().unwrap()).unwrap();
+    assert_eq!((store.size(Kind::Models), store.size(Kind::Tilesets)), (4, 2));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn forgets_missing_content_once_its_cached() {
+    let dir = temp_cache_dir("missing");
+    let store = Store::open(dir.to_str().unwrap()).unwrap();
+    store.record_missing(&key("a"), "a.b3dm", Kind::Models);
+    store.record_missing(&key("b"), "b.json", Kind::Tilesets);
+    store.record_missing(&key("b"), "b.json", Kind::Tilesets);
+
+    let missing = store.missing();
+    let recorded: Vec<(&str, Kind, u64)> = missing.iter().map(|m| (m.path.as_str(), m.kind, m.requests)).collect();
+    assert_eq!(recorded, [("b.json", Kind::Tilesets, 2), ("a.b3dm", Kind::Models, 1)]);
+
+    store.put(&key("b").variant("br"), Kind::Tilesets, "application/json", b"{}", &Validators::default(), "").unwrap();
+    let missing: Vec<String> = store.missing().into_iter().map(|m| m.path).collect();
+    assert_eq!(missing, ["a.b3dm"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn migrates_the_flat_layout_into_the_store() {
+    let dir = temp_cache_dir("migrate");
+    let cache_dir = dir.to_str().unwrap().to_string();
+    let source = Source::new(None, "https://tiles.example.com/", Auth::None, "", cache_dir.clone());
+    let tilesets = PathBuf::from(source.tileset_dir());
+    let b3dms = PathBuf::from(source.b3dm_dir());
+    let glbs = PathBuf::from(source.glb_dir());
+
+    let validators = Validators { etag: Some("\"abc\"".to_string()), last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()) };
+    write(&tilesets.join("tileset.json"), b"{\"asset\":{}}");
+    write(&tilesets.join("tileset.json.meta"), &serde_json::to_vec(&validators).unwrap());
+    write(&tilesets.join("tileset.json.gz"), b"compressed");
+    write(&tilesets.join("sub/1tileset.json"), b"{}");
+    write(&tilesets.join("error.json"), b"<html>Bad Gateway</html>");
+    // Models were cached as .b3dm whatever their format
+    write(&b3dms.join("tiles/123model.b3dm"), b"cmpt composite");
+    write(&b3dms.join("tiles/a.b3dm"), b"pnts points");
+    write(&b3dms.join("tiles/broken.b3dm"), b"<html>");
+    write(&glbs.join("tiles/123model.glb"), b"glTF whole");
+    write(&glbs.join("tiles/123model_1_baked.glb"), b"glTF inner");
+    write(&glbs.join("tiles/unknown.glb"), b"glTF orphan");
+
+    let store = Store::open(&cache_dir).unwrap();
+    assert_eq!(migrate::migrate_flat_layout(&store, &source, Converter::Native), 6);
+
+    let tileset = store.get(&source.key("tileset.json", "")).unwrap();
+    assert_eq!(tileset.validators, validators);
+    assert_eq!(fs::read(&tileset.path).unwrap(), b"{\"asset\":{}}");
+    assert!(store.get(&source.key("sub/1tileset.json", "")).is_some());
+    assert!(store.get(&source.key("error.json", "")).is_none());
+
+    // The extension comes from the magic, and a 123model is kept under the name it may have been requested as
+    let model = store.get(&source.key("tiles/123model.cmpt", "")).unwrap();
+    assert_eq!(store.get(&source.key("tiles/123model", "")).unwrap().blob, model.blob);
+    assert!(store.get(&source.key("tiles/123model.b3dm", "")).is_none());
+    assert!(store.get(&source.key("tiles/a.pnts", "")).is_some());
+    assert!(store.get(&source.key("tiles/a", "")).is_none());
+    assert!(store.get(&source.key("tiles/broken.b3dm", "")).is_none());
+
+    for path in ["tiles/123model.cmpt", "tiles/123model"] {
+        let glb = store.get(&source.key(path, "glb")).unwrap();
+        assert_eq!(fs::read(&glb.path).unwrap(), b"glTF whole");
+        assert_eq!(glb.params, convert::glb_params(Converter::Native, &model.blob));
+        assert!(store.get(&source.key(path, "glb;tile=1;baked")).is_some());
+    }
+    assert!(store.get(&source.key("tiles/unknown", "glb")).is_none());
+
+    // Only the store is left
+    let mut left: Vec<String> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    left.retain(|name| !name.starts_with("index.sqlite3"));
+    assert_eq!(left, ["blobs"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// An upstream answering every request with the current body and no validators
+fn serve(body: Arc<Mutex<String>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+                line.clear();
+            }
+            let body = body.lock().unwrap().clone();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            let _ = stream.write_all((head + &body).as_bytes());
+        }
+    });
+    url
+}
+
+fn tileset_json(uris: &[&str]) -> String {
+    let contents: Vec<String> = uris.iter().map(|uri| format!("{{\"uri\":\"{}\"}}", uri)).collect();
+    format!(
+        "{{\"asset\":{{\"version\":\"1.0\"}},\"geometricError\":10,\"root\":{{\"boundingVolume\":{{\"sphere\":[0,0,0,1]}},\"geometricError\":1,\"contents\":[{}]}}}}",
+        contents.join(",")
+    )
+}
+
+#[test]
+fn invalidates_the_models_of_tilesets_that_changed_upstream() {
+    let dir = temp_cache_dir("revalidate");
+    let store = Store::open(dir.to_str().unwrap()).unwrap();
+    let served = Arc::new(Mutex::new(tileset_json(&["../0/1model.b3dm", "2model.b3dm?v=1", "sub/tileset.json"])));
+    let source = Source::new(None, &serve(Arc::clone(&served)), Auth::None, "", dir.to_str().unwrap().to_string());
+    let policy = RetryPolicy { retries: 0, base_delay: Duration::ZERO, max_delay: Duration::ZERO, breaker_threshold: 5, breaker_cooldown: Duration::ZERO };
+    let client = reqwest::blocking::Client::new();
+
+    let cached = served.lock().unwrap().clone();
+    store.put(&source.key("a/tileset.json", ""), Kind::Tilesets, "application/json", cached.as_bytes(), &Validators::default(), "").unwrap();
+    for (path, variant, kind) in [("0/1model.b3dm", "", Kind::Models), ("0/1model.b3dm", "glb", Kind::Glbs), ("a/2model.b3dm", "", Kind::Models), ("a/3model.b3dm", "", Kind::Models)] {
+        store.put(&source.key(path, variant), kind, "application/octet-stream", path.as_bytes(), &Validators::default(), "").unwrap();
+    }
+    store.put(&source.key("a/sub/tileset.json", ""), Kind::Tilesets, "application/json", b"{}", &Validators::default(), "").unwrap();
+
+    // The same tileset again changes nothing
+    let revalidation = freshness::revalidate_tileset(&store, &client, &source, "a/tileset.json", &policy).unwrap();
+    assert!(matches!(revalidation, Revalidation::Unchanged), "{:?}", revalidation);
+    assert!(store.get(&source.key("0/1model.b3dm", "glb")).is_some());
+
+    let changed = tileset_json(&["3model.b3dm"]);
+    *served.lock().unwrap() = changed.clone();
+    let revalidation = freshness::revalidate_tileset(&store, &client, &source, "a/tileset.json", &policy).unwrap();
+    let Revalidation::Changed { contents, invalidated } = revalidation else {
+        panic!("{:?}", revalidation);
+    };
+    assert_eq!((contents.as_str(), invalidated), (changed.as_str(), 3));
+    assert_eq!(fs::read_to_string(store.get(&source.key("a/tileset.json", "")).unwrap().path).unwrap(), changed);
+    // The models the previous tileset referenced are gone, in every variant
+    for (path, variant) in [("0/1model.b3dm", ""), ("0/1model.b3dm", "glb"), ("a/2model.b3dm", "")] {
+        assert!(store.get(&source.key(path, variant)).is_none(), "{} {}", path, variant);
+    }
+    // External tilesets have their own max-age, and the new tileset's models are left
+    assert!(store.get(&source.key("a/sub/tileset.json", "")).is_some());
+    assert!(store.get(&source.key("a/3model.b3dm", "")).is_some());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
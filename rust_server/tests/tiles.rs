@@ -0,0 +1,531 @@
+use serde_json::{json, Value};
+use tileset_conversion_server::{
+    b3dm,
+    cmpt::{self, Cmpt},
+    convert,
+    glb::{self, Glb},
+    i3dm::{self, I3dm, Instancing},
+    pnts,
+    table::Table,
+};
+
+fn glb_bytes(json: Value, bin: Vec<u8>) -> Vec<u8> {
+    Glb { json, bin }.to_bytes()
+}
+
+// A b3dm with the current 28 byte header, without a batch table
+fn b3dm_bytes(feature_table: Value, glb: &[u8]) -> Vec<u8> {
+    let mut feature_table = serde_json::to_vec(&feature_table).unwrap();
+    while !(28 + feature_table.len()).is_multiple_of(8) {
+        feature_table.push(b' ');
+    }
+    let mut bytes = b"b3dm".to_vec();
+    for value in [1, 28 + feature_table.len() + glb.len(), feature_table.len(), 0, 0, 0] {
+        bytes.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+    bytes.extend_from_slice(&feature_table);
+    bytes.extend_from_slice(glb);
+    bytes
+}
+
+#[test]
+fn rejects_glb_json_that_isnt_an_object() {
+    for json in [json!([]), json!("glTF"), json!(null)] {
+        assert!(Glb::from_bytes(&glb_bytes(json.clone(), Vec::new())).is_err(), "{}", json);
+    }
+}
+
+#[test]
+fn rejects_b3dm_with_rtc_center_and_non_object_gltf() {
+    let tile = b3dm_bytes(json!({ "BATCH_LENGTH": 0, "RTC_CENTER": [1, 2, 3] }), &glb_bytes(json!([]), Vec::new()));
+
+    assert!(b3dm::b3dm_to_glb(&tile).is_err());
+}
+
+fn minimal_glb() -> Vec<u8> {
+    glb_bytes(json!({ "asset": { "version": "2.0" } }), Vec::new())
+}
+
+fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+#[test]
+fn reads_current_and_legacy_b3dm_headers() {
+    let glb = minimal_glb();
+    let batch_table = br#"{"id":[7,8]}"#;
+    let length = |header: usize, body: usize| (header + body + glb.len()) as u32;
+
+    let current = b3dm_bytes(json!({ "BATCH_LENGTH": 2 }), &glb);
+    // [magic, version, byteLength, batchLength, batchTableByteLength]
+    let mut legacy_20 = b"b3dm".to_vec();
+    legacy_20.extend(words(&[1, length(20, batch_table.len()), 2, batch_table.len() as u32]));
+    legacy_20.extend_from_slice(batch_table);
+    legacy_20.extend_from_slice(&glb);
+    // [magic, version, byteLength, batchTableJsonByteLength, batchTableBinaryByteLength, batchLength]
+    let mut legacy_24 = b"b3dm".to_vec();
+    legacy_24.extend(words(&[1, length(24, batch_table.len()), batch_table.len() as u32, 0, 2]));
+    legacy_24.extend_from_slice(batch_table);
+    legacy_24.extend_from_slice(&glb);
+    // Without a batch table the glTF magic follows the legacy header right away
+    let mut legacy_20_without_batch_table = b"b3dm".to_vec();
+    legacy_20_without_batch_table.extend(words(&[1, length(20, 0), 2, 0]));
+    legacy_20_without_batch_table.extend_from_slice(&glb);
+
+    let cases = [
+        ("current", current, json!({})),
+        ("legacy 20 byte header", legacy_20, json!({ "id": [7, 8] })),
+        ("legacy 24 byte header", legacy_24, json!({ "id": [7, 8] })),
+        ("legacy 20 byte header without batch table", legacy_20_without_batch_table, json!({})),
+    ];
+    for (name, bytes, batch_table) in cases {
+        let tile = b3dm::B3dm::from_bytes(&bytes).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        assert_eq!(tile.batch_length(), 2, "{}", name);
+        assert_eq!(tile.batch_table.json, batch_table, "{}", name);
+        assert_eq!(tile.glb, glb, "{}", name);
+        assert_eq!(tile.to_glb().unwrap(), glb, "{}", name);
+    }
+}
+
+#[test]
+fn rejects_b3dm_tables_beyond_the_tile() {
+    let mut bytes = b3dm_bytes(json!({ "BATCH_LENGTH": 0 }), &minimal_glb());
+    // Claims a batch table binary larger than the whole tile
+    bytes[24..28].copy_from_slice(&1000u32.to_le_bytes());
+
+    assert!(b3dm::B3dm::from_bytes(&bytes).is_err());
+    assert!(b3dm::B3dm::from_bytes(b"b3dm").is_err());
+    assert!(b3dm::B3dm::from_bytes(&minimal_glb()).is_err());
+}
+
+#[test]
+fn carries_rtc_center_over_as_cesium_rtc() {
+    let tile = b3dm_bytes(json!({ "BATCH_LENGTH": 0, "RTC_CENTER": [1.5, 2.0, -3.0] }), &minimal_glb());
+    let glb = Glb::from_bytes(&b3dm::b3dm_to_glb(&tile).unwrap()).unwrap();
+
+    assert_eq!(glb.json["extensions"]["CESIUM_RTC"]["center"], json!([1.5, 2.0, -3.0]));
+    assert_eq!(glb.json["extensionsUsed"], json!(["CESIUM_RTC"]));
+}
+
+#[test]
+fn reads_table_properties_from_json_and_binary() {
+    let mut binary = Vec::new();
+    for v in [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0] {
+        binary.extend_from_slice(&v.to_le_bytes());
+    }
+    let table = Table { json: json!({
+        "BATCH_LENGTH": 2,
+        "RTC_CENTER": [10, 20, 30],
+        "QUANTIZED_VOLUME_OFFSET": { "byteOffset": 4 },
+        "POSITION": { "byteOffset": 0 },
+        "NO_OFFSET": {},
+        "TOO_LONG": { "byteOffset": 16 },
+    }), binary };
+
+    assert_eq!(table.global_u32("BATCH_LENGTH"), Some(2));
+    assert_eq!(table.global_vec3("RTC_CENTER"), Some([10.0, 20.0, 30.0]));
+    assert_eq!(table.global_vec3("QUANTIZED_VOLUME_OFFSET"), Some([2.0, 3.0, 4.0]));
+    assert_eq!(table.global_vec3("MISSING"), None);
+    assert_eq!(table.property_f32("POSITION", 2, 3).unwrap(), Some(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]));
+    assert_eq!(table.property_f32("MISSING", 2, 3).unwrap(), None);
+    assert!(table.property_f32("NO_OFFSET", 1, 1).is_err());
+    assert!(table.property_f32("TOO_LONG", 2, 3).is_err());
+}
+
+#[test]
+fn parses_table_json_with_padding() {
+    let table = Table::from_slices(b"{\"BATCH_LENGTH\":4}  \0\0", &[]).unwrap();
+    assert_eq!(table.global_u32("BATCH_LENGTH"), Some(4));
+
+    // An empty JSON header is an empty table
+    assert_eq!(Table::from_slices(b"    ", &[]).unwrap().json, json!({}));
+}
+
+// A composite of already serialized inner tiles
+fn cmpt_bytes(tiles: &[Vec<u8>]) -> Vec<u8> {
+    let length = 16 + tiles.iter().map(Vec::len).sum::<usize>();
+    let mut bytes = b"cmpt".to_vec();
+    bytes.extend(words(&[1, length as u32, tiles.len() as u32]));
+    tiles.iter().for_each(|tile| bytes.extend_from_slice(tile));
+    bytes
+}
+
+// A GLB with one node, mesh, accessor and buffer view over `bin`
+fn mesh_glb(bin: Vec<u8>, center: Option<[f64; 3]>) -> Glb {
+    let mut json = json!({
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0 }],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }],
+        "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 1, "type": "SCALAR" }],
+        "bufferViews": [{ "buffer": 0, "byteLength": bin.len() }],
+        "buffers": [{ "byteLength": bin.len() }],
+    });
+    if let Some(center) = center {
+        json["extensions"] = json!({ "CESIUM_RTC": { "center": center } });
+        json["extensionsUsed"] = json!(["CESIUM_RTC"]);
+    }
+    Glb { json, bin }
+}
+
+#[test]
+fn splits_and_flattens_composites() {
+    let a = b3dm_bytes(json!({ "BATCH_LENGTH": 0 }), &minimal_glb());
+    let b = b3dm_bytes(json!({ "BATCH_LENGTH": 1 }), &minimal_glb());
+    let c = b3dm_bytes(json!({ "BATCH_LENGTH": 2 }), &minimal_glb());
+    let nested = cmpt_bytes(&[b.clone(), c.clone()]);
+    let bytes = cmpt_bytes(&[a.clone(), nested.clone()]);
+    let composite = Cmpt::from_bytes(&bytes).unwrap();
+
+    assert_eq!(composite.tiles, vec![&a[..], &nested[..]]);
+    assert_eq!(composite.flatten().unwrap(), vec![&a[..], &b[..], &c[..]]);
+}
+
+#[test]
+fn limits_how_deep_composites_are_nested() {
+    let tile = b3dm_bytes(json!({ "BATCH_LENGTH": 0 }), &minimal_glb());
+    let mut nested = cmpt_bytes(std::slice::from_ref(&tile));
+    for _ in 1..cmpt::MAX_NESTING {
+        nested = cmpt_bytes(&[nested]);
+    }
+    assert_eq!(Cmpt::from_bytes(&nested).unwrap().flatten().unwrap(), vec![&tile[..]]);
+
+    let too_deep = cmpt_bytes(&[nested]);
+    assert!(Cmpt::from_bytes(&too_deep).unwrap().flatten().is_err());
+    assert!(convert::tile_to_glb(&too_deep, &convert::Options::default()).is_err());
+}
+
+#[test]
+fn rejects_composites_with_truncated_inner_tiles() {
+    let tile = b3dm_bytes(json!({ "BATCH_LENGTH": 0 }), &minimal_glb());
+    let mut too_many = cmpt_bytes(std::slice::from_ref(&tile));
+    too_many[12..16].copy_from_slice(&2u32.to_le_bytes());
+    let mut too_long = cmpt_bytes(&[tile]);
+    too_long[24..28].copy_from_slice(&10_000u32.to_le_bytes());
+
+    for (name, bytes) in [("more tiles than it holds", too_many), ("inner tile longer than the composite", too_long), ("no magic", minimal_glb())] {
+        assert!(Cmpt::from_bytes(&bytes).is_err(), "{}", name);
+    }
+}
+
+#[test]
+fn merges_glbs_remapping_indices_and_buffers() {
+    let merged = glb::merge(vec![mesh_glb(vec![1, 2, 3], None), mesh_glb(vec![4; 8], None)]).unwrap();
+    let json = &merged.json;
+
+    // The first BIN chunk is padded to 4 bytes before the second one
+    assert_eq!(merged.bin, [1, 2, 3, 0, 4, 4, 4, 4, 4, 4, 4, 4]);
+    assert_eq!(json["buffers"], json!([{ "byteLength": 12 }]));
+    assert_eq!(json["bufferViews"][1]["byteOffset"], 4);
+    assert_eq!(json["bufferViews"][1]["buffer"], 0);
+    assert_eq!(json["accessors"][1]["bufferView"], 1);
+    assert_eq!(json["meshes"][1]["primitives"][0]["attributes"]["POSITION"], 1);
+    assert_eq!(json["nodes"], json!([{ "mesh": 0 }, { "mesh": 1 }]));
+    assert_eq!(json["scenes"], json!([{ "nodes": [0, 1] }]));
+    assert!(json.get("extensions").is_none());
+}
+
+#[test]
+fn wraps_inputs_whose_rtc_centers_differ() {
+    let cases = [
+        ("shared center", vec![Some([1.0, 2.0, 3.0]), Some([1.0, 2.0, 3.0])], Some(json!([1.0, 2.0, 3.0])), vec![None, None]),
+        ("different centers", vec![Some([1.0, 2.0, 3.0]), Some([4.0, 5.0, 6.0])], None, vec![Some(json!([1.0, 3.0, -2.0])), Some(json!([4.0, 6.0, -5.0]))]),
+        ("one without a center", vec![Some([1.0, 2.0, 3.0]), None], None, vec![Some(json!([1.0, 3.0, -2.0])), None]),
+    ];
+    for (name, centers, shared, translations) in cases {
+        let merged = glb::merge(centers.iter().map(|c| mesh_glb(vec![0; 4], *c)).collect()).unwrap();
+        let json = &merged.json;
+
+        assert_eq!(json.pointer("/extensions/CESIUM_RTC/center").cloned(), shared, "{}", name);
+        let roots = json["scenes"][0]["nodes"].as_array().unwrap();
+        assert_eq!(roots.len(), 2, "{}", name);
+        for (i, (root, translation)) in roots.iter().zip(&translations).enumerate() {
+            let root = &json["nodes"][root.as_u64().unwrap() as usize];
+            match translation {
+                // The wrapper holds the root of that input, which still references its own mesh
+                Some(translation) => {
+                    assert_eq!(&root["translation"], translation, "{}", name);
+                    let child = root["children"][0].as_u64().unwrap() as usize;
+                    assert_eq!(json["nodes"][child]["mesh"], i, "{}", name);
+                }
+                None => assert_eq!(root["mesh"], i, "{}", name),
+            }
+        }
+    }
+}
+
+#[test]
+fn converts_whole_composites_or_single_inner_tiles() {
+    let tile = |glb: Glb| b3dm_bytes(json!({ "BATCH_LENGTH": 0 }), &glb.to_bytes());
+    let composite = [tile(mesh_glb(vec![1; 4], None)), tile(mesh_glb(vec![2; 4], None)), b"xxxx\x01\0\0\0\x0c\0\0\0".to_vec()];
+    let options = convert::Options::default();
+
+    let merged = Glb::from_bytes(&convert::tile_to_glb(&cmpt_bytes(&composite[..2]), &options).unwrap()).unwrap();
+    assert_eq!(merged.json["meshes"].as_array().unwrap().len(), 2);
+    // A composite isn't served with some of its tiles missing
+    let e = convert::tile_to_glb(&cmpt_bytes(&composite), &options).unwrap_err();
+    assert!(e.contains("inner tile 2"), "{}", e);
+    assert!(convert::tile_to_glb(&cmpt_bytes(&[]), &options).is_err());
+
+    // Each inner tile can still be converted on its own
+    let composite = cmpt_bytes(&composite);
+    let second = Glb::from_bytes(&convert::inner_tile_to_glb(&composite, 1, &options).unwrap()).unwrap();
+    assert_eq!(second.bin, vec![2; 4]);
+    assert!(convert::inner_tile_to_glb(&composite, 2, &options).is_err());
+    assert!(convert::inner_tile_to_glb(&composite, 3, &options).is_err());
+    assert!(convert::inner_tile_to_glb(&tile(mesh_glb(vec![1; 4], None)), 1, &options).is_err());
+}
+
+fn floats(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+// A feature table JSON and binary padded to 8 bytes, like tiles lay them out
+fn padded_table(json: Value, mut binary: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+    let mut json = serde_json::to_vec(&json).unwrap();
+    while !json.len().is_multiple_of(8) {
+        json.push(b' ');
+    }
+    while !binary.len().is_multiple_of(8) {
+        binary.push(0);
+    }
+    (json, binary)
+}
+
+// An i3dm with an embedded GLB and without a batch table
+fn i3dm_bytes(feature_table: Value, binary: Vec<u8>, glb: &[u8]) -> Vec<u8> {
+    let (json, binary) = padded_table(feature_table, binary);
+    let mut bytes = b"i3dm".to_vec();
+    bytes.extend(words(&[1, (32 + json.len() + binary.len() + glb.len()) as u32, json.len() as u32, binary.len() as u32, 0, 0, 1]));
+    bytes.extend(json);
+    bytes.extend(binary);
+    bytes.extend_from_slice(glb);
+    bytes
+}
+
+// The floats of an accessor of a converted GLB
+fn accessor_values(glb: &Glb, accessor: &Value) -> Vec<f32> {
+    let accessor = &glb.json["accessors"][accessor.as_u64().unwrap() as usize];
+    let view = &glb.json["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+    let start = view["byteOffset"].as_u64().unwrap_or(0) as usize;
+    let length = view["byteLength"].as_u64().unwrap() as usize;
+    glb.bin[start..start + length].chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+}
+
+fn assert_close(actual: &[f64], expected: &[f64], name: &str) {
+    assert_eq!(actual.len(), expected.len(), "{}", name);
+    for (a, e) in actual.iter().zip(expected) {
+        assert!((a - e).abs() < 1e-4, "{}: {:?} != {:?}", name, actual, expected);
+    }
+}
+
+#[test]
+fn decodes_i3dm_instance_frames_positions_and_scales() {
+    let radius = 6378137.0;
+    let cases = [
+        (
+            "NORMAL_UP and NORMAL_RIGHT",
+            json!({ "INSTANCES_LENGTH": 1, "POSITION": { "byteOffset": 0 }, "NORMAL_UP": { "byteOffset": 12 }, "NORMAL_RIGHT": { "byteOffset": 24 } }),
+            floats(&[1.0, 2.0, 3.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0]),
+            [1.0, 2.0, 3.0],
+            [[0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]],
+            [1.0; 3],
+        ),
+        (
+            "EAST_NORTH_UP on the equator",
+            json!({ "INSTANCES_LENGTH": 1, "RTC_CENTER": [radius, 0.0, 0.0], "POSITION": { "byteOffset": 0 }, "EAST_NORTH_UP": true }),
+            floats(&[0.0, 0.0, 0.0]),
+            [0.0, 0.0, 0.0],
+            [[0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]],
+            [1.0; 3],
+        ),
+        (
+            "POSITION_QUANTIZED",
+            json!({
+                "INSTANCES_LENGTH": 1,
+                "QUANTIZED_VOLUME_OFFSET": [10, 20, 30],
+                "QUANTIZED_VOLUME_SCALE": [65535, 65535, 65535],
+                "POSITION_QUANTIZED": { "byteOffset": 0 },
+            }),
+            [1u16, 2, 3].iter().flat_map(|v| v.to_le_bytes()).collect(),
+            [11.0, 22.0, 33.0],
+            [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            [1.0; 3],
+        ),
+        (
+            "SCALE and SCALE_NON_UNIFORM",
+            json!({ "INSTANCES_LENGTH": 1, "POSITION": { "byteOffset": 0 }, "SCALE": { "byteOffset": 12 }, "SCALE_NON_UNIFORM": { "byteOffset": 16 } }),
+            floats(&[0.0, 0.0, 0.0, 2.0, 1.0, 3.0, 0.5]),
+            [0.0, 0.0, 0.0],
+            [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            [2.0, 6.0, 1.0],
+        ),
+    ];
+    for (name, feature_table, binary, translation, rotation, scale) in cases {
+        let tile = I3dm::from_bytes(&i3dm_bytes(feature_table, binary, &minimal_glb())).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        let instances = tile.instances().unwrap_or_else(|e| panic!("{}: {}", name, e));
+
+        assert_eq!(instances.len(), 1, "{}", name);
+        assert_close(&instances[0].translation, &translation, name);
+        assert_close(&instances[0].rotation.concat(), &rotation.concat(), name);
+        assert_close(&instances[0].scale, &scale, name);
+    }
+}
+
+#[test]
+fn rejects_i3dm_without_positions() {
+    let tile = I3dm::from_bytes(&i3dm_bytes(json!({ "INSTANCES_LENGTH": 1 }), Vec::new(), &minimal_glb())).unwrap();
+    assert!(tile.instances().is_err());
+
+    let quantized = json!({ "INSTANCES_LENGTH": 1, "POSITION_QUANTIZED": { "byteOffset": 0 } });
+    let tile = I3dm::from_bytes(&i3dm_bytes(quantized, vec![0; 8], &minimal_glb())).unwrap();
+    assert!(tile.instances().is_err());
+}
+
+#[test]
+fn converts_z_up_instances_to_y_up_gltf_transforms() {
+    // A quarter turn about Z-up is a quarter turn about Y-up in glTF, and the up and forward scales swap
+    let half = 0.5f64.sqrt();
+    let cases = [
+        ("translation", floats(&[1.0, 2.0, 3.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 1.0]), [1.0, 3.0, -2.0], [0.0, 0.0, 0.0, 1.0], [1.0, 1.0, 1.0]),
+        ("rotation and scale", floats(&[1.0, 2.0, 3.0, 0.0, 1.0, 0.0, -1.0, 0.0, 0.0, 1.0, 2.0, 3.0]), [1.0, 3.0, -2.0], [0.0, half, 0.0, half], [1.0, 3.0, 2.0]),
+        ("mirrored", floats(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, -1.0, 1.0, 1.0]), [0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0], [-1.0, 1.0, 1.0]),
+    ];
+    for (name, binary, translation, rotation, scale) in cases {
+        let feature_table = json!({
+            "INSTANCES_LENGTH": 1,
+            "POSITION": { "byteOffset": 0 },
+            "NORMAL_RIGHT": { "byteOffset": 12 },
+            "NORMAL_UP": { "byteOffset": 24 },
+            "SCALE_NON_UNIFORM": { "byteOffset": 36 },
+        });
+        let tile = i3dm_bytes(feature_table, binary, &mesh_glb(vec![0; 4], None).to_bytes());
+        let glb = Glb::from_bytes(&i3dm::i3dm_to_glb(&tile, Instancing::Extension).unwrap()).unwrap();
+
+        let node = &glb.json["nodes"][glb.json["scenes"][0]["nodes"][0].as_u64().unwrap() as usize];
+        let attributes = &node["extensions"]["EXT_mesh_gpu_instancing"]["attributes"];
+        let values = |name: &str| accessor_values(&glb, &attributes[name]).into_iter().map(f64::from).collect::<Vec<_>>();
+        assert_close(&values("TRANSLATION"), &translation, name);
+        assert_close(&values("ROTATION"), &rotation, name);
+        assert_close(&values("SCALE"), &scale, name);
+        assert_eq!(glb.json["extensionsUsed"], json!(["EXT_mesh_gpu_instancing"]), "{}", name);
+    }
+}
+
+#[test]
+fn bakes_instances_with_the_transform_of_their_mesh_node() {
+    let mut glb = mesh_glb(vec![0; 4], None);
+    glb.json["nodes"] = json!([{ "children": [1] }, { "mesh": 0, "translation": [0, 0, 5] }]);
+    let feature_table = json!({ "INSTANCES_LENGTH": 2, "POSITION": { "byteOffset": 0 } });
+    let tile = i3dm_bytes(feature_table, floats(&[1.0, 2.0, 3.0, -1.0, 0.0, 0.0]), &glb.to_bytes());
+
+    let glb = Glb::from_bytes(&i3dm::i3dm_to_glb(&tile, Instancing::Baked).unwrap()).unwrap();
+    let roots = glb.json["scenes"][0]["nodes"].as_array().unwrap().clone();
+    assert_eq!(roots.len(), 2);
+    let translations: Vec<Vec<f64>> = roots.iter().map(|root| {
+        let node = &glb.json["nodes"][root.as_u64().unwrap() as usize];
+        assert_eq!(node["mesh"], 0);
+        node["matrix"].as_array().unwrap()[12..15].iter().map(|v| v.as_f64().unwrap()).collect()
+    }).collect();
+    assert_close(&translations[0], &[1.0, 3.0, 3.0], "first instance");
+    assert_close(&translations[1], &[-1.0, 0.0, 5.0], "second instance");
+}
+
+// A pnts without a batch table
+fn pnts_bytes(feature_table: Value, binary: Vec<u8>) -> Vec<u8> {
+    let (json, binary) = padded_table(feature_table, binary);
+    let mut bytes = b"pnts".to_vec();
+    bytes.extend(words(&[1, (28 + json.len() + binary.len()) as u32, json.len() as u32, binary.len() as u32, 0, 0]));
+    bytes.extend(json);
+    bytes.extend(binary);
+    bytes
+}
+
+fn primitive(glb: &Glb) -> &Value {
+    &glb.json["meshes"][0]["primitives"][0]
+}
+
+#[test]
+fn converts_point_positions_to_y_up_with_bounds() {
+    let quantized: Vec<u8> = [0u16, 0, 0, 65535, 65535, 65535].iter().flat_map(|v| v.to_le_bytes()).collect();
+    let cases = [
+        ("POSITION", json!({ "POINTS_LENGTH": 2, "POSITION": { "byteOffset": 0 } }), floats(&[1.0, 2.0, 3.0, -1.0, 4.0, 0.0])),
+        (
+            "POSITION_QUANTIZED",
+            json!({
+                "POINTS_LENGTH": 2,
+                "POSITION_QUANTIZED": { "byteOffset": 0 },
+                "QUANTIZED_VOLUME_OFFSET": [-1, 2, 0],
+                "QUANTIZED_VOLUME_SCALE": [2, 2, 3],
+            }),
+            quantized,
+        ),
+    ];
+    for (name, feature_table, binary) in cases {
+        let glb = Glb::from_bytes(&pnts::pnts_to_glb(&pnts_bytes(feature_table, binary)).unwrap()).unwrap();
+        let position = &primitive(&glb)["attributes"]["POSITION"];
+        let accessor = &glb.json["accessors"][position.as_u64().unwrap() as usize];
+
+        assert_eq!(primitive(&glb)["mode"], 0, "{}", name);
+        assert_eq!(accessor["count"], 2, "{}", name);
+        // Z-up (x, y, z) is Y-up (x, z, -y)
+        assert_eq!(accessor["min"], json!([-1.0, 0.0, -4.0]), "{}", name);
+        assert_eq!(accessor["max"], json!([1.0, 3.0, -2.0]), "{}", name);
+    }
+}
+
+#[test]
+fn rejects_point_clouds_without_points() {
+    for feature_table in [json!({ "POINTS_LENGTH": 0, "POSITION": { "byteOffset": 0 } }), json!({ "POSITION": { "byteOffset": 0 } })] {
+        assert!(pnts::pnts_to_glb(&pnts_bytes(feature_table, Vec::new())).is_err());
+    }
+}
+
+#[test]
+fn applies_constant_rgba_only_to_points_without_colors() {
+    let position = floats(&[0.0, 0.0, 0.0]);
+    let mut with_rgb = position.clone();
+    with_rgb.extend([255, 0, 0]);
+    let cases = [
+        ("CONSTANT_RGBA", json!({ "POINTS_LENGTH": 1, "POSITION": { "byteOffset": 0 }, "CONSTANT_RGBA": [255, 255, 255, 128] }), position.clone(), false, [1.0, 1.0, 1.0, 128.0 / 255.0]),
+        (
+            "RGB and CONSTANT_RGBA",
+            json!({ "POINTS_LENGTH": 1, "POSITION": { "byteOffset": 0 }, "RGB": { "byteOffset": 12 }, "CONSTANT_RGBA": [0, 0, 0, 255] }),
+            with_rgb,
+            true,
+            [1.0; 4],
+        ),
+        ("no colors", json!({ "POINTS_LENGTH": 1, "POSITION": { "byteOffset": 0 } }), position, false, [1.0; 4]),
+    ];
+    for (name, feature_table, binary, has_colors, base_color) in cases {
+        let glb = Glb::from_bytes(&pnts::pnts_to_glb(&pnts_bytes(feature_table, binary)).unwrap()).unwrap();
+        let factor: Vec<f64> = glb.json["materials"][0]["pbrMetallicRoughness"]["baseColorFactor"].as_array().unwrap().iter().map(|v| v.as_f64().unwrap()).collect();
+
+        assert_eq!(primitive(&glb)["attributes"].get("COLOR_0").is_some(), has_colors, "{}", name);
+        assert_close(&factor, &base_color, name);
+    }
+}
+
+#[test]
+fn decodes_point_colors_normals_and_batch_ids() {
+    let mut binary = floats(&[0.0, 0.0, 0.0]);
+    binary.extend(0xF800u16.to_le_bytes()); // RGB565 red
+    binary.extend([255, 255]); // NORMAL_OCT16P for Z-up (0, 0, -1)
+    binary.push(5); // BATCH_ID
+    let feature_table = json!({
+        "POINTS_LENGTH": 1,
+        "POSITION": { "byteOffset": 0 },
+        "RGB565": { "byteOffset": 12 },
+        "NORMAL_OCT16P": { "byteOffset": 14 },
+        "BATCH_ID": { "byteOffset": 16, "componentType": "UNSIGNED_BYTE" },
+    });
+    let glb = Glb::from_bytes(&pnts::pnts_to_glb(&pnts_bytes(feature_table, binary)).unwrap()).unwrap();
+    let attributes = &primitive(&glb)["attributes"];
+    let values = |name: &str| accessor_values(&glb, &attributes[name]).into_iter().map(f64::from).collect::<Vec<_>>();
+
+    assert_close(&values("COLOR_0"), &[1.0, 0.0, 0.0], "RGB565");
+    assert_close(&values("NORMAL"), &[0.0, -1.0, 0.0], "NORMAL_OCT16P");
+    assert_close(&values("_FEATURE_ID_0"), &[5.0], "BATCH_ID");
+    assert_eq!(primitive(&glb)["extensions"]["EXT_mesh_features"]["featureIds"][0]["featureCount"], 6);
+}
@@ -0,0 +1,240 @@
+use std::{fs, path::Path};
+
+use serde_json::Value;
+use tileset_conversion_server::{
+    http, rewrite,
+    source::{Auth, Source},
+    tileset::{self, Refine, Tileset},
+    upgrade,
+};
+
+fn read_fixture(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("test").join(name);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("Unable to read {}: {}", path.display(), e))
+}
+
+#[test]
+fn parses_1_0_fixture() {
+    let tileset = Tileset::from_json(&read_fixture("3D-tiles-1_0.json")).unwrap();
+
+    assert_eq!(tileset.asset.version, "1.0");
+    assert_eq!(tileset.root.refine, Some(Refine::Add));
+    let uris = tileset.content_uris();
+    assert_eq!(uris.len(), 28);
+    assert_eq!(uris[0], "000tileset.json");
+    assert!(uris.iter().all(|uri| tileset::is_tileset_path(uri)));
+}
+
+#[test]
+fn round_trips_unknown_fields() {
+    let tileset = Tileset::from_json(&read_fixture("3D-tiles-1_0.json")).unwrap();
+    // The fixture has a boundingVolume next to root, which isn't part of the spec
+    assert!(tileset.other.contains_key("boundingVolume"));
+
+    let reparsed = Tileset::from_json(&tileset.to_json().unwrap()).unwrap();
+    assert_eq!(reparsed, tileset);
+}
+
+#[test]
+fn finds_content_in_contents_arrays_and_legacy_urls() {
+    let json = r#"{
+        "asset": { "version": "1.1" },
+        "geometricError": 100,
+        "root": {
+            "boundingVolume": { "sphere": [0, 0, 0, 10] },
+            "geometricError": 10,
+            "contents": [{ "uri": "a.b3dm" }, { "uri": "b.pnts", "group": 0 }],
+            "children": [{
+                "boundingVolume": { "sphere": [0, 0, 0, 5] },
+                "geometricError": 0,
+                "content": { "url": "sub/c.json?v=2" }
+            }]
+        }
+    }"#;
+    let tileset = Tileset::from_json(json).unwrap();
+
+    assert_eq!(tileset.content_uris(), vec!["a.b3dm", "b.pnts", "sub/c.json?v=2"]);
+    assert!(tileset::is_tileset_path("sub/c.json?v=2"));
+    assert!(!tileset::is_tileset_path("a.b3dm"));
+}
+
+#[test]
+fn resolves_relative_content_uris() {
+    let base = "https://example.com/tiles/";
+
+    assert_eq!(tileset::resolve_content_uri(base, "tileset.json", "123model.b3dm").as_deref(), Some("123model.b3dm"));
+    assert_eq!(tileset::resolve_content_uri(base, "sub/tileset.json", "../0/1.b3dm?v=1").as_deref(), Some("0/1.b3dm?v=1"));
+    assert_eq!(tileset::resolve_content_uri(base, "tileset.json", "https://other.com/1.b3dm"), None);
+}
+
+// Tiles without their content, recursively
+fn without_content(tile: &Value) -> Value {
+    let mut tile = tile.clone();
+    if let Some(object) = tile.as_object_mut() {
+        object.remove("content");
+        object.remove("contents");
+        if let Some(Value::Array(children)) = object.get_mut("children") {
+            *children = children.iter().map(without_content).collect();
+        }
+    }
+    tile
+}
+
+#[test]
+fn upgrades_1_0_fixture_to_the_1_1_fixture() {
+    let upgraded: Value = serde_json::from_str(&upgrade::upgrade_tileset_json(&read_fixture("3D-tiles-1_0.json")).unwrap()).unwrap();
+    // Written by the same model, so numbers compare equal, e.g. 9000 and 9000.0
+    let expected: Value = serde_json::from_str(&Tileset::from_json(&read_fixture("3D-tiles_1_1.json")).unwrap().to_json().unwrap()).unwrap();
+
+    assert_eq!(upgraded["asset"]["version"], upgrade::UPGRADED_VERSION);
+    // The 1.1 fixture has the same tree without content, so refine, geometric errors and bounding volumes are kept
+    assert_eq!(without_content(&upgraded["root"]), expected["root"]);
+    assert_eq!(upgraded["geometricError"], expected["geometricError"]);
+    assert_eq!(upgraded["boundingVolume"], expected["boundingVolume"]);
+
+    // External tilesets are served as they are, and upgraded when they're requested
+    let upgraded = Tileset::from_json(&upgraded.to_string()).unwrap();
+    assert_eq!(upgraded.content_uris(), Tileset::from_json(&read_fixture("3D-tiles-1_0.json")).unwrap().content_uris());
+}
+
+#[test]
+fn upgrades_legacy_content_urls() {
+    let json = r#"{
+        "asset": { "version": "1.0" },
+        "extensionsUsed": ["3DTILES_content_gltf", "3DTILES_metadata"],
+        "extensionsRequired": ["3DTILES_content_gltf"],
+        "geometricError": 100,
+        "root": {
+            "boundingVolume": { "sphere": [0, 0, 0, 10] },
+            "geometricError": 10,
+            "refine": "REPLACE",
+            "content": { "url": "a.b3dm?v=1" },
+            "children": [{
+                "boundingVolume": { "sphere": [0, 0, 0, 5] },
+                "geometricError": 0,
+                "contents": [{ "uri": "b.glb" }, { "uri": "sub/tileset.json" }, { "uri": "123model" }]
+            }]
+        }
+    }"#;
+    let tileset = Tileset::from_json(&upgrade::upgrade_tileset_json(json).unwrap()).unwrap();
+
+    assert_eq!(tileset.asset.version, "1.1");
+    assert_eq!(tileset.root.refine, Some(Refine::Replace));
+    // Models only get their GLB URI once they're resolved against the source
+    assert_eq!(tileset.content_uris(), vec!["a.b3dm?v=1", "b.glb", "sub/tileset.json", "123model"]);
+    assert!(tileset.root.content.as_ref().unwrap().url.is_none());
+    assert_eq!(tileset.extensions_used, Some(vec!["3DTILES_metadata".to_string()]));
+    assert_eq!(tileset.extensions_required, None);
+}
+
+#[test]
+fn maps_converted_uris_back_to_their_source_models() {
+    let cases = [
+        ("123model.b3dm", "123model.b3dm.glb", Some("123model.b3dm")),
+        ("tiles/1.cmpt?v=2", "tiles/1.cmpt.glb?v=2", Some("tiles/1.cmpt?v=2")),
+        ("trees.i3dm", "trees.i3dm.glb", Some("trees.i3dm")),
+        ("lidar/0.pnts", "lidar/0.pnts.glb", Some("lidar/0.pnts")),
+        ("sub/123model", "sub/123model.glb", Some("sub/123model")),
+        // GLB and glTF content upstream is served as it is
+        ("tiles/a.glb", "tiles/a.glb", None),
+        ("tiles/model.glb", "tiles/model.glb", None),
+        ("a.gltf", "a.gltf", None),
+        ("sub/tileset.json", "sub/tileset.json", None),
+    ];
+    for (uri, served, source) in cases {
+        assert_eq!(upgrade::glb_uri(uri), served, "{}", uri);
+        assert_eq!(upgrade::source_uri(served).as_deref(), source, "{}", uri);
+    }
+}
+
+#[test]
+fn leaves_content_hosted_elsewhere_as_it_is() {
+    let json = r#"{
+        "asset": { "version": "1.0" },
+        "geometricError": 100,
+        "root": {
+            "boundingVolume": { "sphere": [0, 0, 0, 10] },
+            "geometricError": 10,
+            "contents": [
+                { "uri": "https://other.example.com/x/1model.b3dm" },
+                { "url": "https://tiles.example.com/data/2model.b3dm" },
+                { "uri": "3model" }
+            ]
+        }
+    }"#;
+    let mut tileset = Tileset::from_json(json).unwrap();
+    upgrade::upgrade_tileset(&mut tileset);
+    rewrite::rewrite_content_uris(&mut tileset, "tileset.json", "https://tiles.example.com/data/", None);
+
+    assert_eq!(tileset.content_uris(), vec!["https://other.example.com/x/1model.b3dm", "2model.b3dm.glb", "3model.glb"]);
+}
+
+#[test]
+fn requests_the_same_upstream_urls_as_the_fetcher() {
+    let source = Source::new(None, "https://tiles.example.com/data/", Auth::None, "", String::new());
+    let json = r#"{
+        "asset": { "version": "1.0" },
+        "geometricError": 100,
+        "root": {
+            "boundingVolume": { "sphere": [0, 0, 0, 10] },
+            "geometricError": 10,
+            "contents": [{ "uri": "../0/2model.b3dm?v=3&sig=a%2Fb+c" }, { "uri": "3model" }, { "uri": "../1/tileset.json?v=3" }]
+        }
+    }"#;
+    let original = Tileset::from_json(json).unwrap();
+    let mut served = original.clone();
+    rewrite::rewrite_content_uris(&mut served, "sub/tileset.json", &source.url, None);
+
+    for (uri, served) in original.content_uris().into_iter().zip(served.content_uris()) {
+        // The fetcher fetches what the tileset references
+        let fetched = tileset::resolve_content_uri(&source.url, "sub/tileset.json", uri).unwrap();
+        // Clients resolve the served URI against the tileset on this server, and may add parameters of this server
+        let requested = tileset::resolve_content_uri("http://localhost:7878/", "sub/tileset.json", served).unwrap();
+        let target = format!("/{}{}tile=0", requested, if requested.contains('?') { "&" } else { "?" });
+        let request = http::read_request(&mut format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).unwrap();
+
+        assert_eq!(rewrite::upstream_path(request.path.trim_start_matches('/'), &request), fetched, "{}", uri);
+    }
+    // Both cache it without the query
+    assert_eq!(source.key("0/2model.b3dm?v=3", "").url, "https://tiles.example.com/data/0/2model.b3dm");
+}
+
+#[test]
+fn rewrites_content_uris_of_nested_tilesets() {
+    let json = r#"{
+        "asset": { "version": "1.0" },
+        "geometricError": 100,
+        "root": {
+            "boundingVolume": { "sphere": [0, 0, 0, 10] },
+            "geometricError": 10,
+            "contents": [
+                { "uri": "../../0/1model.b3dm" },
+                { "uri": "../c/2model.b3dm?v=3#x" },
+                { "uri": "3model" },
+                { "uri": "sub/tileset.json?v=2" },
+                { "uri": "../../other.json" }
+            ]
+        }
+    }"#;
+    let original = Tileset::from_json(json).unwrap();
+    let rewritten = |tileset_path: &str, public_url: Option<&str>| {
+        let mut tileset = original.clone();
+        rewrite::rewrite_content_uris(&mut tileset, tileset_path, "https://tiles.example.com/data/", public_url);
+        tileset.content_uris().into_iter().map(str::to_string).collect::<Vec<String>>()
+    };
+
+    // Relative to the tileset, and its own query doesn't change its directory
+    let relative = ["../../0/1model.b3dm.glb", "../c/2model.b3dm.glb?v=3#x", "3model.glb", "sub/tileset.json?v=2", "../../other.json"];
+    assert_eq!(rewritten("a/b/tileset.json", None), relative);
+    assert_eq!(rewritten("a/b/tileset.json?v=1", None), relative);
+
+    let absolute = [
+        "https://cdn.example.com/tiles/0/1model.b3dm.glb",
+        "https://cdn.example.com/tiles/a/c/2model.b3dm.glb?v=3#x",
+        "https://cdn.example.com/tiles/a/b/3model.glb",
+        "https://cdn.example.com/tiles/a/b/sub/tileset.json?v=2",
+        "https://cdn.example.com/tiles/other.json",
+    ];
+    assert_eq!(rewritten("a/b/tileset.json", Some("https://cdn.example.com/tiles")), absolute);
+    assert_eq!(rewritten("a/b/tileset.json", Some("https://cdn.example.com/tiles/")), absolute);
+}
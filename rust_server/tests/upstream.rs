@@ -0,0 +1,114 @@
+use std::{thread, time::Duration};
+
+use tileset_conversion_server::upstream::{self, CircuitBreaker, FetchError, RetryPolicy};
+
+fn policy(cooldown: Duration) -> RetryPolicy {
+    RetryPolicy { retries: 0, base_delay: Duration::ZERO, max_delay: Duration::ZERO, breaker_threshold: 2, breaker_cooldown: cooldown }
+}
+
+#[test]
+fn opens_after_consecutive_failures_and_closes_after_a_probe() {
+    let policy = policy(Duration::from_millis(20));
+    let breaker = CircuitBreaker::new();
+
+    breaker.record_failure(&policy);
+    breaker.record_success();
+    breaker.record_failure(&policy);
+    assert!(!breaker.is_open(), "a success resets the failures");
+    breaker.record_failure(&policy);
+    assert!(breaker.is_open());
+    assert!(!breaker.allow_request());
+
+    thread::sleep(Duration::from_millis(30));
+    assert!(breaker.allow_request(), "the probe after the cooldown");
+    assert!(!breaker.allow_request(), "only one probe at a time");
+    breaker.record_success();
+    assert!(!breaker.is_open());
+    assert!(breaker.allow_request());
+}
+
+#[test]
+fn reopens_when_the_probe_fails() {
+    let policy = policy(Duration::from_millis(20));
+    let breaker = CircuitBreaker::new();
+    breaker.record_failure(&policy);
+    breaker.record_failure(&policy);
+
+    thread::sleep(Duration::from_millis(30));
+    assert!(breaker.allow_request());
+    breaker.record_failure(&policy);
+    assert!(!breaker.allow_request());
+}
+
+#[test]
+fn lets_another_probe_through_when_one_is_released() {
+    let policy = policy(Duration::from_millis(20));
+    let breaker = CircuitBreaker::new();
+    breaker.record_failure(&policy);
+    breaker.record_failure(&policy);
+
+    thread::sleep(Duration::from_millis(30));
+    assert!(breaker.allow_request());
+    breaker.release();
+    assert!(breaker.allow_request(), "the released probe doesn't keep the breaker half-open");
+
+    // Releasing a closed breaker leaves it closed
+    breaker.record_success();
+    breaker.release();
+    assert!(!breaker.is_open());
+}
+
+// A model header claiming byte_length, followed by length - 12 bytes
+fn model(magic: &[u8; 4], byte_length: u32, length: usize) -> Vec<u8> {
+    let mut bytes = magic.to_vec();
+    bytes.extend(1u32.to_le_bytes());
+    bytes.extend(byte_length.to_le_bytes());
+    bytes.resize(length, 0);
+    bytes
+}
+
+#[test]
+fn accepts_only_whole_json_objects_as_tilesets() {
+    assert!(upstream::check_tileset(b"{\"asset\":{\"version\":\"1.0\"}}").is_ok());
+    assert!(upstream::check_tileset("\u{feff}\n  {}\n".as_bytes()).is_ok());
+
+    let invalid: [&[u8]; 6] = [
+        b"<!DOCTYPE html><html><body>502 Bad Gateway</body></html>",
+        b"[]",
+        b"",
+        b"{\"asset\":{\"version\":",
+        b"{\"asset\":{}} trailing",
+        &[b'{', 0xff, b'}'],
+    ];
+    for bytes in invalid {
+        assert!(upstream::check_tileset(bytes).is_err(), "{}", String::from_utf8_lossy(bytes));
+    }
+}
+
+#[test]
+fn accepts_only_whole_models_of_known_formats() {
+    for magic in [b"b3dm", b"cmpt", b"i3dm", b"pnts", b"glTF"] {
+        assert!(upstream::check_model(&model(magic, 40, 40)).is_ok());
+    }
+    // Padding after the tile is fine
+    assert!(upstream::check_model(&model(b"b3dm", 40, 48)).is_ok());
+
+    assert!(upstream::check_model(b"<html><body>Not Found</body></html>").unwrap_err().contains("unknown magic"));
+    assert!(upstream::check_model(&model(b"b3dm", 4096, 1000)).unwrap_err().contains("truncated"));
+    assert!(upstream::check_model(b"b3dm").unwrap_err().contains("too short"));
+    assert!(upstream::check_model(b"").is_err());
+}
+
+#[test]
+fn answers_failed_fetches_with_a_matching_status() {
+    let cases = [
+        (FetchError::NotFound("a".to_string()), 404),
+        (FetchError::Offline("a".to_string()), 404),
+        (FetchError::Timeout("a".to_string()), 504),
+        (FetchError::BadGateway("a".to_string()), 502),
+        (FetchError::Cache("a".to_string()), 500),
+    ];
+    for (error, status) in cases {
+        assert_eq!(error.status(), status, "{:?}", error);
+    }
+}